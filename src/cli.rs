@@ -18,69 +18,300 @@
  */
 
 extern crate clap;
-use clap::{crate_version, App, Arg}; // SubCommand
+use clap::{crate_version, App, AppSettings, Arg, SubCommand};
+
+fn database_arg() -> Arg<'static, 'static> {
+    Arg::with_name("database")
+        .help("Database to be used")
+        .long("db")
+        .takes_value(true)
+        .required(false)
+}
+
+fn databases_arg() -> Arg<'static, 'static> {
+    database_arg()
+        .help("Databases or groups of databases to be used, separated with commas")
+}
+
+fn all_arg() -> Arg<'static, 'static> {
+    Arg::with_name("all")
+        .help("Query / update all databases, except those setting hide_from_all")
+        .long("all")
+        .takes_value(false)
+        .required(false)
+}
+
+/// Arguments shared by the `query` subcommand and the bare `lolcate PATTERN` form.
+fn query_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        databases_arg(),
+        all_arg(),
+        Arg::with_name("type")
+            .help("One or several file types to search, separated with commas")
+            .long("type")
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("mime")
+            .help("One or several MIME types to search, separated with commas, e.g. image/*. Requires databases indexed with detect_mime = true")
+            .long("mime")
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("exec")
+            .help("Execute a command for each path name found, in parallel. In its arguments, {} is replaced by the path name, {/} by its base name, {//} by its parent directory, {.} by the path name without extension and {/.} by the base name without extension. When there is no placeholder, the path name is appended. The command ends with ';' or with the arguments")
+            .short("x")
+            .long("exec")
+            .takes_value(true)
+            .min_values(1)
+            .allow_hyphen_values(true)
+            .value_terminator(";")
+            .value_name("CMD")
+            .conflicts_with_all(&["exec_batch", "format", "json", "jsonl", "summary"])
+            .required(false),
+        Arg::with_name("exec_batch")
+            .help("Execute a command once, with all the path names found as arguments. Accepts the same placeholders as --exec")
+            .short("X")
+            .long("exec-batch")
+            .takes_value(true)
+            .min_values(1)
+            .allow_hyphen_values(true)
+            .value_terminator(";")
+            .value_name("CMD")
+            .conflicts_with_all(&["format", "json", "jsonl", "summary"])
+            .required(false),
+        Arg::with_name("threads")
+            .help("Number of commands run in parallel by --exec. Defaults to the number of CPUs")
+            .short("j")
+            .long("threads")
+            .takes_value(true)
+            .value_name("N")
+            .requires("exec")
+            .required(false),
+        Arg::with_name("interactive")
+            .help("Pick path names in a full-screen terminal interface, narrowing them down as patterns are typed. Up/Down moves, Tab marks, Enter prints the marked or selected path names, Ctrl-O opens the selected one, Ctrl-Y copies them to the clipboard and Esc cancels")
+            .long("interactive")
+            .takes_value(false)
+            .conflicts_with("summary")
+            .required(false),
+        Arg::with_name("no_daemon")
+            .help("Read the data files even when the daemon is running")
+            .long("no-daemon")
+            .takes_value(false)
+            .required(false),
+        Arg::with_name("ignore_case")
+            .help("Search the given patterns case-insensitively. Default is \"smart-case\", i.e. patterns are searched case-insensitively when all in lowercase, and sensitively otherwise.")
+            .short("i")
+            .long("ignore-case")
+            .takes_value(false)
+            .required(false),
+        Arg::with_name("case_sensitive")
+            .help("Search the given patterns case-sensitively")
+            .short("s")
+            .long("case-sensitive")
+            .takes_value(false)
+            .conflicts_with("ignore_case")
+            .required(false),
+        Arg::with_name("limit")
+            .help("Stop after printing N path names")
+            .short("l")
+            .long("limit")
+            .takes_value(true)
+            .value_name("N")
+            .required(false),
+        Arg::with_name("sort")
+            .help("Sort the path names of all the queried databases by path, name, size or mtime. Sorting by size or mtime requires databases storing metadata")
+            .long("sort")
+            .takes_value(true)
+            .value_name("KEY")
+            .possible_values(&["path", "name", "size", "mtime"])
+            .required(false),
+        Arg::with_name("reverse")
+            .help("Sort in reverse order")
+            .long("reverse")
+            .takes_value(false)
+            .requires("sort")
+            .required(false),
+        Arg::with_name("rank")
+            .help("Put the path names opened the most frequently and recently first, the others being sorted by --sort or by path. Path names are recorded when opened from --interactive or given to --exec with history = true in the defaults, or using --touch")
+            .long("rank")
+            .takes_value(true)
+            .value_name("RANKING")
+            .possible_values(&["frecency"])
+            .required(false),
+        Arg::with_name("touch")
+            .help("Record a visit of PATH in the history used by --rank, instead of querying")
+            .long("touch")
+            .takes_value(true)
+            .value_name("PATH")
+            .multiple(true)
+            .number_of_values(1)
+            .required(false),
+        Arg::with_name("unique")
+            .help("Print path names found in several databases only once")
+            .long("unique")
+            .takes_value(false)
+            .required(false),
+        Arg::with_name("format")
+            .help("Output format: plain (one path name per line), null (path names terminated by NUL characters), json (an array of records), jsonl (one record per line), or a template such as '{db}\\t{mtime:%F}\\t{path}'. Templates accept the {path}, {basename}, {dirname}, {ext}, {db}, {relpath}, {kind}, {size}, {mtime}, {mtime:FORMAT} and {mime} placeholders")
+            .long("format")
+            .takes_value(true)
+            .value_name("FORMAT")
+            .required(false),
+        Arg::with_name("json")
+            .help("Write the matches as a JSON array of records, like --format json")
+            .long("json")
+            .takes_value(false)
+            .conflicts_with_all(&["format", "jsonl"])
+            .required(false),
+        Arg::with_name("jsonl")
+            .help("Write the matches as JSON records, one per line, like --format jsonl")
+            .long("jsonl")
+            .takes_value(false)
+            .conflicts_with_all(&["format"])
+            .required(false),
+        Arg::with_name("summary")
+            .help("With JSON output, end with a record counting the matches of each database")
+            .long("summary")
+            .takes_value(false)
+            .required(false),
+        Arg::with_name("basename_pattern")
+            .help("Match only the base name against the specified PATTERN. Can be supplied multiple times, e.g. -b PATTERN1 -b PATTERN2")
+            .short("b")
+            .long("basename")
+            .takes_value(true)
+            .value_name("PATTERN")
+            .number_of_values(1)
+            .required(false),
+        Arg::with_name("pattern")
+            .value_name("PATTERN")
+            .min_values(1)
+            .required(false),
+    ]
+}
 
 pub fn build_cli() -> App<'static, 'static> {
     App::new("Lolcate")
-        .version(&crate_version!()[..])
+        .version(crate_version!())
         .author("Nicolas Girard <girard.nicolas@gmail.com>")
         .about("Find files by name -- A better locate / mlocate / updatedb")
+        .setting(AppSettings::VersionlessSubcommands)
+        // Don't mistake patterns resembling a subcommand name for a typo
+        .setting(AppSettings::AllowExternalSubcommands)
+        .after_help("When no subcommand is given, the arguments are handled as by `lolcate query`.")
+        .arg(Arg::with_name("system")
+            .help("Use the system-wide databases, configured in /etc/lolcate and stored in /var/lib/lolcate")
+            .long("system")
+            .takes_value(false)
+            .global(true)
+            .required(false))
+        .arg(Arg::with_name("color")
+            .help("When to use colors")
+            .long("color")
+            .takes_value(true)
+            .value_name("WHEN")
+            .possible_values(&["auto", "always", "never"])
+            .global(true)
+            .required(false))
+        .arg(Arg::with_name("stats")
+            .help("Display statistics about all databases, or those given by --db, like lolcate stats")
+            .long("stats")
+            .takes_value(false)
+            .conflicts_with_all(&["pattern", "update", "create", "info", "basename_pattern"])
+            .required(false))
+        // Mode flags kept for compatibility with versions prior to subcommands
         .arg(Arg::with_name("create")
             .help("Create a database")
             .long("create")
             .takes_value(false)
-            .conflicts_with_all(&["pattern", "update", "info"])
+            .conflicts_with_all(&["pattern", "update", "info", "all", "ignore_case", "basename_pattern"])
             .required(false)
+            .hidden(true)
             )
         .arg(Arg::with_name("info")
             .help("Display configuration informations and existing databases")
             .long("info")
             .takes_value(false)
-            .conflicts_with_all(&["pattern", "update", "create", "database"])
+            .conflicts_with_all(&["pattern", "update", "create", "all", "ignore_case", "basename_pattern"])
             .required(false)
+            .hidden(true)
             )
         .arg(Arg::with_name("update")
             .help("Update database")
             .long("update")
             .takes_value(false)
-            .conflicts_with_all(&["pattern", "create", "info"])
+            .conflicts_with_all(&["pattern", "create", "info", "ignore_case", "basename_pattern"])
             .required(false)
+            .hidden(true)
             )
-        .arg(Arg::with_name("database")
-            .help("Database to be used / created")
-            .long("db")
-            .takes_value(true)
-            .required(false)
-            .default_value("default"))
-        .arg(Arg::with_name("type")
-            .help("One or several file types to search, separated with commas")
-            .long("type")
-            .takes_value(true)
-            .required(false))
-        .arg(Arg::with_name("all")
-            .help("Query / update all databases")
-            .long("all")
-            .takes_value(false)
-            .conflicts_with_all(&["create", "info"])
-            .required(false))
-        .arg(Arg::with_name("ignore_case")
-            .help("Search the given patterns case-insensitively. Default is \"smart-case\", i.e. patterns are searched case-insensitively when all in lowercase, and sensitively otherwise.")
-            .short("i")
-            .long("ignore-case")
-            .takes_value(false)
-            .required(false)
-            .conflicts_with_all(&["create", "info", "update"]))
-        .arg(Arg::with_name("basename_pattern")
-            .help("Match only the base name against the specified PATTERN. Can be supplied multiple times, e.g. -b PATTERN1 -b PATTERN2")
-            .short("b")
-            .long("basename")
-            .takes_value(true)
-            .value_name("PATTERN")
-            .number_of_values(1)
-            .required(false)
-            .conflicts_with_all(&["create", "info", "update"]))
-        .arg(Arg::with_name("pattern")
-            .value_name("PATTERN")
-            .min_values(1)
-            .required(false))
+        .args(&query_args())
+        .subcommand(SubCommand::with_name("create")
+            .about("Create a database")
+            .arg(database_arg().help("Database to be created"))
+            .arg(Arg::with_name("single_file")
+                .help("Keep the ignore rules in the configuration file instead of a separate ignores file")
+                .long("single-file")
+                .takes_value(false)
+                .required(false)))
+        .subcommand(SubCommand::with_name("update")
+            .about("Update one or all databases")
+            .arg(databases_arg().help("Databases or groups of databases to be updated, separated with commas"))
+            .arg(all_arg().help("Update all databases, except those setting hide_from_all")))
+        .subcommand(SubCommand::with_name("info")
+            .about("Display configuration informations and existing databases"))
+        .subcommand(SubCommand::with_name("daemon")
+            .about("Keep the databases in memory and answer queries over a Unix socket, reloading them when they are updated"))
+        .subcommand(SubCommand::with_name("serve")
+            .about("Answer queries over HTTP with JSON, on /search and /databases")
+            .arg(Arg::with_name("listen")
+                .help("Address and port to listen on, e.g. 127.0.0.1:8080")
+                .long("listen")
+                .takes_value(true)
+                .value_name("ADDR")
+                .required(true))
+            .arg(Arg::with_name("allow_remote")
+                .help("Allow listening on addresses other than loopback ones, making the databases searchable by anyone who can reach them")
+                .long("allow-remote")
+                .takes_value(false)
+                .required(false)))
+        .subcommand(SubCommand::with_name("stats")
+            .about("Display statistics about all or some databases")
+            .arg(databases_arg().help("Databases or groups of databases to describe, separated with commas. Defaults to all databases")))
+        .subcommand(SubCommand::with_name("query")
+            .about("Query one or all databases (default)")
+            .args(&query_args()))
+        .subcommand(SubCommand::with_name("edit")
+            .about("Edit the configuration of a database using $VISUAL or $EDITOR")
+            .arg(database_arg().help("Database whose configuration will be edited"))
+            .arg(Arg::with_name("ignores")
+                .help("Edit the ignores file instead of the configuration file")
+                .long("ignores")
+                .takes_value(false)
+                .conflicts_with("global")
+                .required(false))
+            .arg(Arg::with_name("global")
+                .help("Edit the global configuration file")
+                .long("global")
+                .takes_value(false)
+                .required(false)))
+        .subcommand(SubCommand::with_name("remove")
+            .about("Remove a database, along with its configuration and data files")
+            .arg(Arg::with_name("name")
+                .help("Database to be removed")
+                .value_name("NAME")
+                .required(true))
+            .arg(Arg::with_name("yes")
+                .help("Don't ask for confirmation")
+                .short("y")
+                .long("yes")
+                .takes_value(false)
+                .required(false)))
+        .subcommand(SubCommand::with_name("rename")
+            .about("Rename a database, along with its configuration and data files")
+            .arg(Arg::with_name("old_name")
+                .help("Current name of the database")
+                .value_name("OLD")
+                .required(true))
+            .arg(Arg::with_name("new_name")
+                .help("New name of the database")
+                .value_name("NEW")
+                .required(true)))
 }
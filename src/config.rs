@@ -20,46 +20,303 @@
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::process;
-use std::{convert, fs, io::prelude::*, path};
+use std::{convert, fmt, fs, io::prelude::*, path};
 use toml::de::Error;
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub description: String,
+    #[serde(default)]
+    pub hide_from_all: bool,
     #[serde(deserialize_with = "deserialize::deserialize")]
-    pub dirs: Vec<path::PathBuf>,
+    pub dirs: Vec<Dir>,
     #[serde(default)]
     pub skip: Skip,
     #[serde(default)]
     pub gitignore: bool,
     pub ignore_symlinks: bool,
     pub ignore_hidden: bool,
+    #[serde(default)]
+    pub ignore: Vec<String>,
+    pub max_depth: Option<usize>,
+    pub min_depth: Option<usize>,
+    #[serde(default)]
+    pub same_file_system: bool,
+    #[serde(default)]
+    pub prune_fs_types: Vec<String>,
+    #[serde(default)]
+    pub on_missing: OnMissing,
+    pub volume: Option<Volume>,
+    #[serde(default)]
+    pub include_regex: Vec<String>,
+    #[serde(default)]
+    pub exclude_regex: Vec<String>,
+    #[serde(default)]
+    pub detect_mime: bool,
+    #[serde(default)]
+    pub store_metadata: bool,
+    #[serde(default)]
+    pub sort_entries: bool,
+    #[serde(default)]
+    pub types: HashMap<String, TypeDef>,
 }
 
-#[derive(Debug, Deserialize, PartialEq, Copy, Clone)]
+/// The filesystem a database is bound to. The paths of such a database are
+/// stored relative to the root of the filesystem, wherever it is mounted.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Volume {
+    pub uuid: Option<String>,
+    pub label: Option<String>,
+}
+
+impl fmt::Display for Volume {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.label, &self.uuid) {
+            (Some(label), _) => write!(f, "{}", label),
+            (None, Some(uuid)) => write!(f, "{}", uuid),
+            (None, None) => write!(f, "(unspecified volume)"),
+        }
+    }
+}
+
+/// An entry of `dirs`, either given as a plain path or as a table overriding
+/// some of the database-wide options for this directory. The path is kept
+/// unexpanded until the database is updated.
+#[derive(Debug)]
+pub struct Dir {
+    pub path: String,
+    pub skip: Option<Skip>,
+    pub gitignore: Option<bool>,
+    pub ignore_symlinks: Option<bool>,
+    pub ignore_hidden: Option<bool>,
+    pub max_depth: Option<usize>,
+    pub min_depth: Option<usize>,
+    pub same_file_system: Option<bool>,
+    pub optional: Option<bool>,
+    pub on_missing: Option<OnMissing>,
+    pub ignore: Vec<String>,
+}
+
+/// A directory to index, along with the options resolved for it.
+#[derive(Debug)]
+pub struct Root {
+    pub path: path::PathBuf,
+    pub skip: Skip,
+    pub gitignore: bool,
+    pub ignore_symlinks: bool,
+    pub ignore_hidden: bool,
+    pub max_depth: Option<usize>,
+    pub min_depth: Option<usize>,
+    pub same_file_system: bool,
+    pub on_missing: OnMissing,
+    pub ignore: Vec<String>,
+    /// Whether `path` is the glob pattern of a dir which couldn't be
+    /// expanded, kept so that its previous entries can be carried over
+    pub unexpanded: bool,
+}
+
+impl Config {
+    /// Resolves the directories to index, expanding `~`, environment
+    /// variables and glob patterns in their paths. Relative paths are
+    /// resolved against `base`, the mount point of the volume of the
+    /// database, if any.
+    pub fn roots(&self, base: Option<&path::Path>) -> Result<Vec<Root>, String> {
+        let mut roots = Vec::new();
+        for dir in &self.dirs {
+            let on_missing = match (dir.on_missing, dir.optional) {
+                (Some(on_missing), _) => on_missing,
+                (None, Some(true)) => OnMissing::Skip,
+                _ => self.on_missing,
+            };
+            let paths = match expand::expand_dir(&dir.path, base) {
+                Ok(paths) => paths,
+                Err(error) if on_missing == OnMissing::Fail => return Err(error),
+                Err(error) if on_missing == OnMissing::Skip => {
+                    eprintln!("{} Skipping it.", error);
+                    continue;
+                }
+                Err(error) => {
+                    match expand::dir_pattern(&dir.path, base) {
+                        Some(pattern) => {
+                            eprintln!("{} Keeping its previous entries.", error);
+                            roots.push(Root {
+                                unexpanded: true,
+                                ..self.root(dir, pattern, on_missing)
+                            });
+                        }
+                        None => eprintln!(
+                            "{} Skipping it, as its previous entries can't be told apart.",
+                            error
+                        ),
+                    }
+                    continue;
+                }
+            };
+            for path in paths {
+                roots.push(self.root(dir, path, on_missing));
+            }
+        }
+        Ok(roots)
+    }
+
+    fn root(&self, dir: &Dir, path: path::PathBuf, on_missing: OnMissing) -> Root {
+        Root {
+            path,
+            skip: dir.skip.unwrap_or(self.skip),
+            gitignore: dir.gitignore.unwrap_or(self.gitignore),
+            ignore_symlinks: dir.ignore_symlinks.unwrap_or(self.ignore_symlinks),
+            ignore_hidden: dir.ignore_hidden.unwrap_or(self.ignore_hidden),
+            max_depth: dir.max_depth.or(self.max_depth),
+            min_depth: dir.min_depth.or(self.min_depth),
+            same_file_system: dir.same_file_system.unwrap_or(self.same_file_system),
+            on_missing,
+            ignore: dir.ignore.clone(),
+            unexpanded: false,
+        }
+    }
+
+    /// Returns the indexed directories which can currently be expanded,
+    /// silently leaving out the others.
+    pub fn root_paths(&self, base: Option<&path::Path>) -> Vec<path::PathBuf> {
+        self.dirs
+            .iter()
+            .filter_map(|dir| expand::expand_dir(&dir.path, base).ok())
+            .flatten()
+            .collect()
+    }
+}
+
+/// What to do when a directory to index is missing or cannot be accessed.
+#[derive(Debug, Default, Deserialize, PartialEq, Copy, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum OnMissing {
+    /// Index the other directories
+    Skip,
+    /// Carry over the entries of the directory from the previous index
+    KeepPrevious,
+    /// Abort the update
+    #[default]
+    Fail,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq, Copy, Clone)]
 pub enum Skip {
+    #[default]
     None,
     Dirs,
     Files,
 }
 
-impl Default for Skip {
-    fn default() -> Self {
-        Skip::None
-    }
+#[derive(Debug, Default, Deserialize)]
+pub struct GlobalConfig {
+    #[serde(default)]
+    pub defaults: Defaults,
+    #[serde(default)]
+    pub groups: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    pub types: HashMap<String, TypeDef>,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct GlobalConfig {
-    pub types: HashMap<String, String>,
+/// Default options, overridden by the command line.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Defaults {
+    pub database: Option<String>,
+    /// Unless set, patterns are searched using smart case
+    pub ignore_case: Option<bool>,
+    pub color: Option<ColorWhen>,
+    pub limit: Option<usize>,
+    pub format: Option<String>,
+    /// Whether the path names opened from queries are recorded for --rank
+    pub history: Option<bool>,
+    /// Interval after which info warns about databases not updated, e.g. "7d"
+    pub stale_after: Option<String>,
+}
+
+/// Parses an interval such as "90m", "12h", "7d" or "2w" into seconds.
+pub fn parse_interval(interval: &str) -> Result<i64, String> {
+    let invalid = || format!("Invalid interval: {}. Expected e.g. 90m, 12h, 7d or 2w.", interval);
+    let split = interval.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+    let count: i64 = interval[..split].parse().map_err(|_| invalid())?;
+    let unit = match &interval[split..] {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 24 * 3600,
+        "w" => 7 * 24 * 3600,
+        _ => return Err(invalid()),
+    };
+    Ok(count * unit)
 }
 
-pub fn read_toml_file<'a, 'de, P: ?Sized, T>(
+/// When to use colors in the output.
+#[derive(Debug, Deserialize, PartialEq, Copy, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorWhen {
+    Auto,
+    Always,
+    Never,
+}
+
+/// The definition of a file type: a regex, a list of other types, or a
+/// table combining extensions, globs, a regex, other types and MIME types.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum TypeDef {
+    Regex(String),
+    Types(Vec<String>),
+    Spec(TypeSpec),
+}
+
+#[derive(Debug, Default, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TypeSpec {
+    #[serde(default)]
+    pub extensions: Vec<String>,
+    #[serde(default)]
+    pub globs: Vec<String>,
+    pub regex: Option<String>,
+    #[serde(default)]
+    pub types: Vec<String>,
+    #[serde(default)]
+    pub mime: Vec<String>,
+}
+
+impl fmt::Display for TypeDef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TypeDef::Regex(regex) => write!(f, "{}", regex),
+            TypeDef::Types(types) => write!(f, "{}", types.join(", ")),
+            TypeDef::Spec(spec) => {
+                let mut parts = Vec::new();
+                if !spec.extensions.is_empty() {
+                    parts.push(format!("extensions {}", spec.extensions.join(", ")));
+                }
+                if !spec.globs.is_empty() {
+                    parts.push(format!("globs {}", spec.globs.join(", ")));
+                }
+                if let Some(regex) = &spec.regex {
+                    parts.push(format!("regex {}", regex));
+                }
+                if !spec.types.is_empty() {
+                    parts.push(format!("types {}", spec.types.join(", ")));
+                }
+                if !spec.mime.is_empty() {
+                    parts.push(format!("MIME types {}", spec.mime.join(", ")));
+                }
+                write!(f, "{}", parts.join("; "))
+            }
+        }
+    }
+}
+
+pub fn read_toml_file<'a, 'de, P, T>(
     path: &'a P,
     buffer: &'de mut String,
 ) -> Result<T, Error>
 where
-    P: convert::AsRef<path::Path>,
+    P: convert::AsRef<path::Path> + ?Sized,
     T: Deserialize<'de>,
 {
     let mut configuration_file: fs::File = match fs::OpenOptions::new().read(true).open(path) {
@@ -80,21 +337,228 @@ where
 }
 
 mod deserialize {
+    use super::{Dir, OnMissing, Skip};
     use serde::de::{Deserialize, Deserializer};
-    use std::path;
+    use serde::Deserialize as DeriveDeserialize;
 
-    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<path::PathBuf>, D::Error>
+    #[derive(DeriveDeserialize)]
+    #[serde(deny_unknown_fields)]
+    struct DirTable {
+        path: String,
+        skip: Option<Skip>,
+        gitignore: Option<bool>,
+        ignore_symlinks: Option<bool>,
+        ignore_hidden: Option<bool>,
+        max_depth: Option<usize>,
+        min_depth: Option<usize>,
+        same_file_system: Option<bool>,
+        optional: Option<bool>,
+        on_missing: Option<OnMissing>,
+        #[serde(default)]
+        ignore: Vec<String>,
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<Dir>, D::Error>
     where
         D: Deserializer<'de>,
     {
-        let s = Vec::<String>::deserialize(deserializer)?;
-        s.into_iter()
-            .map(|s| {
-                #[cfg(not(windows))]
-                return expanduser::expanduser(&s).map_err(serde::de::Error::custom);
-                #[cfg(windows)]
-                return Ok(s.into());
+        let specs = Vec::<toml::Value>::deserialize(deserializer)?;
+        specs
+            .into_iter()
+            .map(|spec| {
+                let dir = match spec {
+                    toml::Value::String(s) => Dir {
+                        path: s,
+                        skip: None,
+                        gitignore: None,
+                        ignore_symlinks: None,
+                        ignore_hidden: None,
+                        max_depth: None,
+                        min_depth: None,
+                        same_file_system: None,
+                        optional: None,
+                        on_missing: None,
+                        ignore: Vec::new(),
+                    },
+                    toml::Value::Table(_) => {
+                        let t: DirTable = spec.try_into().map_err(serde::de::Error::custom)?;
+                        Dir {
+                            path: t.path,
+                            skip: t.skip,
+                            gitignore: t.gitignore,
+                            ignore_symlinks: t.ignore_symlinks,
+                            ignore_hidden: t.ignore_hidden,
+                            max_depth: t.max_depth,
+                            min_depth: t.min_depth,
+                            same_file_system: t.same_file_system,
+                            optional: t.optional,
+                            on_missing: t.on_missing,
+                            ignore: t.ignore,
+                        }
+                    }
+                    _ => {
+                        return Err(serde::de::Error::custom(
+                            "expected a path or a table in `dirs`",
+                        ))
+                    }
+                };
+                Ok(dir)
             })
             .collect()
     }
 }
+
+mod expand {
+    use std::env;
+    use std::path;
+
+    /// Replaces `$VAR` and `${VAR}` with the value of the environment variable.
+    fn expand_vars(s: &str) -> Result<String, String> {
+        let mut out = String::with_capacity(s.len());
+        let mut rest = s;
+        while let Some(pos) = rest.find('$') {
+            out.push_str(&rest[..pos]);
+            rest = &rest[pos + 1..];
+            let (name, remainder) = if let Some(braced) = rest.strip_prefix('{') {
+                match braced.find('}') {
+                    Some(end) => (&braced[..end], &braced[end + 1..]),
+                    None => return Err(format!("Unterminated variable in dir {:?}.", s)),
+                }
+            } else {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                (&rest[..end], &rest[end..])
+            };
+            if name.is_empty() {
+                out.push('$');
+                continue;
+            }
+            match env::var(name) {
+                Ok(value) => out.push_str(&value),
+                Err(_) => {
+                    return Err(format!(
+                        "The environment variable {} used in dir {:?} is not set.",
+                        name, s
+                    ))
+                }
+            }
+            rest = remainder;
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+
+    fn expand_user(s: &str) -> Result<path::PathBuf, String> {
+        #[cfg(not(windows))]
+        return expanduser::expanduser(s)
+            .map_err(|e| format!("Cannot expand dir {:?}: {}", s, e));
+        #[cfg(windows)]
+        return Ok(s.into());
+    }
+
+    fn resolve(path: path::PathBuf, base: Option<&path::Path>) -> path::PathBuf {
+        match base {
+            Some(base) if path.is_relative() => base.join(path).components().collect(),
+            _ => path,
+        }
+    }
+
+    /// Expands a configured dir into the list of paths it designates.
+    pub fn expand_dir(
+        s: &str,
+        base: Option<&path::Path>,
+    ) -> Result<Vec<path::PathBuf>, String> {
+        let path = resolve(expand_user(&expand_vars(s)?)?, base);
+        let pattern = path.to_string_lossy();
+        if !pattern.contains(['*', '?', '[']) {
+            return Ok(vec![path]);
+        }
+        let paths: Vec<path::PathBuf> = glob::glob(&pattern)
+            .map_err(|e| format!("Invalid glob pattern in dir {:?}: {}", s, e))?
+            .filter_map(Result::ok)
+            .filter(|p| p.is_dir())
+            .collect();
+        if paths.is_empty() {
+            return Err(format!("The pattern {:?} doesn't match any directory.", s));
+        }
+        Ok(paths)
+    }
+
+    /// Turns a configured dir whose glob pattern doesn't match anything
+    /// into the pattern matching the paths it designated. Returns `None` if
+    /// it uses a variable which isn't set, as the paths it designated can't
+    /// be told apart then.
+    pub fn dir_pattern(s: &str, base: Option<&path::Path>) -> Option<path::PathBuf> {
+        let pattern = expand_user(&expand_vars(s).ok()?).ok()?;
+        Some(resolve(pattern, base))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn expand_vars_replaces_variables() {
+            env::set_var("LOLCATE_TEST_EXPAND", "/srv");
+            assert_eq!(expand_vars("$LOLCATE_TEST_EXPAND/a").unwrap(), "/srv/a");
+            assert_eq!(expand_vars("${LOLCATE_TEST_EXPAND}x").unwrap(), "/srvx");
+            assert_eq!(expand_vars("/a/$/b").unwrap(), "/a/$/b");
+            assert_eq!(expand_vars("/no/vars").unwrap(), "/no/vars");
+        }
+
+        #[test]
+        fn expand_vars_rejects_unset_or_unterminated_variables() {
+            env::remove_var("LOLCATE_TEST_UNSET");
+            assert!(expand_vars("$LOLCATE_TEST_UNSET/a").is_err());
+            assert!(expand_vars("${LOLCATE_TEST_UNSET").is_err());
+        }
+
+        #[test]
+        fn expand_dir_resolves_relative_paths() {
+            let base = path::Path::new("/base/dir");
+            assert_eq!(
+                expand_dir("sub/../x", Some(base)).unwrap(),
+                vec![path::PathBuf::from("/base/dir/sub/../x")]
+            );
+            assert_eq!(
+                expand_dir("/abs", Some(base)).unwrap(),
+                vec![path::PathBuf::from("/abs")]
+            );
+        }
+
+        #[test]
+        fn expand_dir_expands_globs_to_directories() {
+            let dir = tempfile::tempdir().unwrap();
+            std::fs::create_dir(dir.path().join("a1")).unwrap();
+            std::fs::create_dir(dir.path().join("a2")).unwrap();
+            std::fs::write(dir.path().join("a3"), "").unwrap();
+            let pattern = format!("{}/a*", dir.path().display());
+            let mut paths = expand_dir(&pattern, None).unwrap();
+            paths.sort();
+            assert_eq!(paths, vec![dir.path().join("a1"), dir.path().join("a2")]);
+            let pattern = format!("{}/b*", dir.path().display());
+            assert!(expand_dir(&pattern, None).is_err());
+        }
+
+        #[test]
+        fn dir_pattern_keeps_glob_patterns() {
+            env::set_var("LOLCATE_TEST_PATTERN", "/srv");
+            assert_eq!(
+                dir_pattern("$LOLCATE_TEST_PATTERN/*_old", None),
+                Some(path::PathBuf::from("/srv/*_old"))
+            );
+            assert_eq!(
+                dir_pattern("disk*/music", Some(path::Path::new("/media/usb"))),
+                Some(path::PathBuf::from("/media/usb/disk*/music"))
+            );
+        }
+
+        #[test]
+        fn dir_pattern_rejects_unset_variables() {
+            env::remove_var("LOLCATE_TEST_UNSET");
+            assert_eq!(dir_pattern("$LOLCATE_TEST_UNSET/music", None), None);
+            assert_eq!(dir_pattern("/srv/${LOLCATE_TEST_UNSET}_old/*", None), None);
+        }
+    }
+}
@@ -0,0 +1,505 @@
+/*
+ * This file is part of Lolcate.
+ *
+ * Copyright © 2019 Nicolas Girard
+ *
+ * Lolcate is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Lolcate is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Lolcate.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! A daemon keeping the contents of the data files in memory, and answering
+//! queries over a Unix domain socket.
+//!
+//! Clients send requests and the daemon sends responses, each one being a
+//! JSON object on a line of its own. A request queries a single database:
+//!
+//! ```text
+//! {"database":"docs","patterns":["report"],"ignore_case":null,"types":[],"mime":null}
+//! ```
+//!
+//! The daemon answers with a `match` response for each matching entry,
+//! holding the line of the data file with its path name made absolute when
+//! the database is bound to a volume, possibly `warning` responses, and ends
+//! with an `end` response, or an `error` one. Several requests can be sent
+//! over the same connection.
+
+use serde::{Deserialize, Serialize};
+
+/// The part of a request which is common to all the databases of a query.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Query {
+    /// Regexes which the path names must all match
+    pub patterns: Vec<String>,
+    /// Whether the regexes are case-insensitive, smart case applying if unset
+    pub ignore_case: Option<bool>,
+    /// Names of the file types to search
+    pub types: Vec<String>,
+    /// Comma-separated MIME type patterns to search
+    pub mime: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Request {
+    database: String,
+    #[serde(flatten)]
+    query: Query,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Response {
+    Match { line: String },
+    Warning { message: String },
+    Error { message: String },
+    End,
+}
+
+#[cfg(not(windows))]
+pub use self::unix::{serve, Client};
+
+#[cfg(windows)]
+pub use self::unsupported::{serve, Client};
+
+#[cfg(not(windows))]
+mod unix {
+    use super::{Query, Request, Response};
+    use crate::output::Output;
+    use crate::{entry, lolcate_data_path};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io::{self, BufRead, Read, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::{Path, PathBuf};
+    use std::process;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, SystemTime};
+
+    /// How often the data files are checked for changes.
+    const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+
+    fn socket_fn() -> PathBuf {
+        lolcate_data_path().join("daemon.sock")
+    }
+
+    /// The decompressed contents of a data file.
+    struct Data {
+        modified: SystemTime,
+        len: u64,
+        contents: Arc<Vec<u8>>,
+    }
+
+    /// The contents of the data files read so far, by path.
+    #[derive(Default)]
+    struct Cache {
+        files: Mutex<HashMap<PathBuf, Data>>,
+    }
+
+    impl Cache {
+        /// Returns the contents of the data file `path`, reading it again if
+        /// it changed since it was last read.
+        fn load(&self, path: &Path) -> io::Result<Arc<Vec<u8>>> {
+            let metadata = fs::metadata(path)?;
+            let modified = metadata.modified()?;
+            if let Some(data) = self.files.lock().unwrap().get(path) {
+                if data.modified == modified && data.len == metadata.len() {
+                    return Ok(data.contents.clone());
+                }
+            }
+            let mut contents = Vec::new();
+            let read = fs::File::open(path)
+                .and_then(lz4::Decoder::new)
+                .and_then(|mut decoder| decoder.read_to_end(&mut contents));
+            let mut files = self.files.lock().unwrap();
+            if let Err(error) = read {
+                // The data file may be in the middle of being updated
+                return match files.get(path) {
+                    Some(data) => Ok(data.contents.clone()),
+                    None => Err(error),
+                };
+            }
+            eprintln!("Loaded {}", path.display());
+            let contents = Arc::new(contents);
+            files.insert(
+                path.to_path_buf(),
+                Data {
+                    modified,
+                    len: metadata.len(),
+                    contents: contents.clone(),
+                },
+            );
+            Ok(contents)
+        }
+
+        /// Reads the data files which changed again, and forgets the removed
+        /// ones.
+        fn refresh(&self) {
+            let paths: Vec<PathBuf> = self.files.lock().unwrap().keys().cloned().collect();
+            for path in paths {
+                if !path.exists() {
+                    self.files.lock().unwrap().remove(&path);
+                    eprintln!("Unloaded {}", path.display());
+                } else if let Err(error) = self.load(&path) {
+                    eprintln!("Cannot read {}: {}", path.display(), error);
+                }
+            }
+        }
+    }
+
+    /// Answers a request, writing the responses to `out`.
+    fn answer(cache: &Cache, request: &Request, out: &mut impl Write) -> io::Result<()> {
+        let write = |out: &mut dyn Write, response: &Response| -> io::Result<()> {
+            serde_json::to_writer(&mut *out, response)?;
+            out.write_all(b"\n")
+        };
+        match search(cache, request, &mut |response| write(out, &response)) {
+            Ok(()) => write(out, &Response::End),
+            Err(message) => write(out, &Response::Error { message }),
+        }?;
+        out.flush()
+    }
+
+    /// Searches the database of `request`, sending the matches, and the
+    /// warnings, to `send`.
+    fn search(
+        cache: &Cache,
+        request: &Request,
+        send: &mut dyn FnMut(Response) -> io::Result<()>,
+    ) -> Result<(), String> {
+        let query = &request.query;
+        let db_name = &request.database;
+        // The configuration files are cheap to read, and may have changed
+        let global_config = crate::read_global_config(&crate::global_config_fn())?;
+        let type_names: Vec<&str> = query.types.iter().map(String::as_str).collect();
+        let mut databases =
+            crate::searched_databases(vec![db_name.clone()], &type_names, &global_config.types)?;
+        let database = match databases.pop() {
+            Some(database) => database,
+            // None of the requested types is defined for this database
+            None => return Ok(()),
+        };
+        let mime_filter = query.mime.as_deref().map(crate::mime_filter).transpose()?;
+        let patterns_re = query
+            .patterns
+            .iter()
+            .map(|pattern| crate::compile_regex(pattern, query.ignore_case))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|error| format!("Invalid regex: {}", error))?;
+        let volume_location = crate::db_volume_location(database.config.as_ref());
+        if let Some(Err(volume)) = &volume_location {
+            let message = crate::unmounted_volume_warning(db_name, volume);
+            send(Response::Warning { message }).map_err(|error| error.to_string())?;
+        }
+        let contents = cache.load(&database.data_file).map_err(|error| {
+            format!("Cannot read {}: {}", database.data_file.display(), error)
+        })?;
+        let mut result = Ok(());
+        crate::search_entries(
+            &contents[..],
+            volume_location.as_ref(),
+            &patterns_re,
+            &database.types,
+            mime_filter.as_ref(),
+            |path, entry| {
+                let line = format!("{}{}", path, entry.fields());
+                result = send(Response::Match { line });
+                result.is_ok()
+            },
+        )
+        .and(result)
+        .map_err(|error| error.to_string())
+    }
+
+    fn handle(cache: &Cache, stream: UnixStream) -> io::Result<()> {
+        let mut out = io::BufWriter::new(stream.try_clone()?);
+        for line in io::BufReader::new(stream).lines() {
+            match serde_json::from_str::<Request>(&line?) {
+                Ok(request) => answer(cache, &request, &mut out)?,
+                Err(error) => {
+                    let message = format!("Invalid request: {}", error);
+                    serde_json::to_writer(&mut out, &Response::Error { message })?;
+                    out.write_all(b"\n")?;
+                    out.flush()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn serve() -> io::Result<()> {
+        if let crate::Scope::System = crate::scope() {
+            eprintln!("The daemon only serves the databases of the current user.");
+            process::exit(1);
+        }
+        let socket_fn = socket_fn();
+        if socket_fn.exists() {
+            if UnixStream::connect(&socket_fn).is_ok() {
+                eprintln!("The daemon is already running, listening on {}", socket_fn.display());
+                process::exit(1);
+            }
+            // Left behind by a daemon which didn't exit cleanly
+            fs::remove_file(&socket_fn)?;
+        }
+        fs::create_dir_all(lolcate_data_path())?;
+        // Create the socket with restrictive permissions from the start, as
+        // changing them after binding would leave a window during which
+        // other users could connect. No other thread is running yet, so
+        // changing the process-wide umask is fine.
+        let umask = unsafe { libc::umask(0o077) };
+        let listener = UnixListener::bind(&socket_fn);
+        unsafe { libc::umask(umask) };
+        let listener = listener?;
+
+        let cache = Arc::new(Cache::default());
+        for db_name in crate::database_names(lolcate_data_path()) {
+            let db_file = crate::db_fn(&db_name);
+            if db_file.exists() {
+                if let Err(error) = cache.load(&db_file) {
+                    eprintln!("Cannot read {}: {}", db_file.display(), error);
+                }
+            }
+        }
+        {
+            let cache = cache.clone();
+            thread::spawn(move || loop {
+                thread::sleep(RELOAD_INTERVAL);
+                cache.refresh();
+            });
+        }
+        eprintln!("Listening on {}", socket_fn.display());
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let cache = cache.clone();
+                    thread::spawn(move || {
+                        // Clients going away in the middle of a response are fine
+                        let _ = handle(&cache, stream);
+                    });
+                }
+                Err(error) => eprintln!("Cannot accept connection: {}", error),
+            }
+        }
+        Ok(())
+    }
+
+    /// A connection to a running daemon.
+    pub struct Client {
+        query: Query,
+        reader: io::BufReader<UnixStream>,
+        writer: UnixStream,
+    }
+
+    impl Client {
+        /// Connects to the daemon, if it is running, to look up `query`.
+        pub fn connect(query: Query) -> Option<Client> {
+            let stream = UnixStream::connect(socket_fn()).ok()?;
+            Some(Client {
+                query,
+                reader: io::BufReader::new(stream.try_clone().ok()?),
+                writer: stream,
+            })
+        }
+
+        /// Looks up the database `db_name`, writing the matches to `output`.
+        pub fn lookup(&mut self, db_name: &str, output: &mut Output) -> io::Result<()> {
+            let request = Request {
+                database: db_name.to_string(),
+                query: self.query.clone(),
+            };
+            let mut line = serde_json::to_string(&request)?;
+            line.push('\n');
+            self.writer.write_all(line.as_bytes())?;
+            loop {
+                line.clear();
+                if self.reader.read_line(&mut line)? == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "The daemon closed the connection",
+                    ));
+                }
+                match serde_json::from_str(&line)? {
+                    Response::Match { line } => {
+                        let entry = entry::Entry::parse(&line);
+                        if !output.write(entry.path, &entry) {
+                            // The rest of the response won't be read
+                            self.writer.shutdown(std::net::Shutdown::Both)?;
+                            return Ok(());
+                        }
+                    }
+                    Response::Warning { message } => eprintln!("{}", message),
+                    Response::Error { message } => return Err(io::Error::other(message)),
+                    Response::End => return Ok(()),
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::sync::OnceLock;
+
+        /// Creates the database "test" once for all the tests, as its
+        /// location is given by the environment.
+        fn setup() {
+            static DIR: OnceLock<tempfile::TempDir> = OnceLock::new();
+            DIR.get_or_init(|| {
+                let dir = tempfile::tempdir().unwrap();
+                std::env::set_var("LOLCATE_CONFIG_DIR", dir.path().join("config"));
+                std::env::set_var("LOLCATE_DATA_DIR", dir.path().join("data"));
+                let data_file = crate::db_fn("test");
+                fs::create_dir_all(data_file.parent().unwrap()).unwrap();
+                let mut encoder = lz4::EncoderBuilder::new()
+                    .build(fs::File::create(&data_file).unwrap())
+                    .unwrap();
+                writeln!(encoder, "/data/alpha.txt").unwrap();
+                for i in 0..1000 {
+                    writeln!(encoder, "/data/beta/{}.rs", i).unwrap();
+                }
+                encoder.finish().1.unwrap();
+                dir
+            });
+        }
+
+        fn request(database: &str, patterns: &[&str]) -> Request {
+            Request {
+                database: database.to_string(),
+                query: Query {
+                    patterns: patterns.iter().map(|p| p.to_string()).collect(),
+                    ignore_case: None,
+                    types: Vec::new(),
+                    mime: None,
+                },
+            }
+        }
+
+        fn parse_responses(out: &[u8]) -> Vec<serde_json::Value> {
+            out.split(|&b| b == b'\n')
+                .filter(|line| !line.is_empty())
+                .map(|line| serde_json::from_slice(line).unwrap())
+                .collect()
+        }
+
+        /// A writer failing once `remaining` bytes were written, like a
+        /// connection closed by the client.
+        struct Disconnecting {
+            remaining: usize,
+        }
+
+        impl Write for Disconnecting {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if buf.len() > self.remaining {
+                    return Err(io::ErrorKind::BrokenPipe.into());
+                }
+                self.remaining -= buf.len();
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        #[test]
+        fn answer_sends_the_matches_then_end() {
+            setup();
+            let mut out = Vec::new();
+            answer(&Cache::default(), &request("test", &["alpha"]), &mut out).unwrap();
+            let responses = parse_responses(&out);
+            assert_eq!(responses.len(), 2);
+            assert_eq!(responses[0]["type"], "match");
+            assert_eq!(responses[0]["line"], "/data/alpha.txt");
+            assert_eq!(responses[1]["type"], "end");
+        }
+
+        #[test]
+        fn answer_reports_invalid_requests() {
+            setup();
+            let cache = Cache::default();
+            let mut out = Vec::new();
+            answer(&cache, &request("test", &["("]), &mut out).unwrap();
+            let responses = parse_responses(&out);
+            assert_eq!(responses.len(), 1);
+            assert_eq!(responses[0]["type"], "error");
+            assert!(responses[0]["message"]
+                .as_str()
+                .unwrap()
+                .starts_with("Invalid regex"));
+
+            let mut out = Vec::new();
+            answer(&cache, &request("missing", &[]), &mut out).unwrap();
+            assert_eq!(parse_responses(&out)[0]["type"], "error");
+        }
+
+        #[test]
+        fn answer_stops_when_the_client_disconnects() {
+            setup();
+            let cache = Cache::default();
+            let mut out = io::BufWriter::with_capacity(64, Disconnecting { remaining: 1000 });
+            let error = answer(&cache, &request("test", &["beta"]), &mut out).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+            // The data file stays loaded for the next clients
+            let mut out = Vec::new();
+            answer(&cache, &request("test", &["alpha"]), &mut out).unwrap();
+            assert_eq!(parse_responses(&out).len(), 2);
+        }
+
+        #[test]
+        fn handle_answers_each_request_of_a_connection() {
+            setup();
+            let (mut client, server) = UnixStream::pair().unwrap();
+            client.write_all(b"not json\n").unwrap();
+            let mut line = serde_json::to_string(&request("test", &["alpha"])).unwrap();
+            line.push('\n');
+            client.write_all(line.as_bytes()).unwrap();
+            client.shutdown(std::net::Shutdown::Write).unwrap();
+            handle(&Cache::default(), server).unwrap();
+            let mut out = Vec::new();
+            client.read_to_end(&mut out).unwrap();
+            let responses = parse_responses(&out);
+            assert_eq!(responses.len(), 3);
+            assert_eq!(responses[0]["type"], "error");
+            assert!(responses[0]["message"]
+                .as_str()
+                .unwrap()
+                .starts_with("Invalid request"));
+            assert_eq!(responses[1]["type"], "match");
+            assert_eq!(responses[2]["type"], "end");
+        }
+    }
+}
+
+#[cfg(windows)]
+mod unsupported {
+    use super::Query;
+    use crate::output::Output;
+    use std::io;
+    use std::process;
+
+    pub fn serve() -> io::Result<()> {
+        eprintln!("The daemon isn't supported on Windows.");
+        process::exit(1);
+    }
+
+    pub enum Client {}
+
+    impl Client {
+        pub fn connect(_query: Query) -> Option<Client> {
+            None
+        }
+
+        pub fn lookup(&mut self, _db_name: &str, _output: &mut Output) -> io::Result<()> {
+            match *self {}
+        }
+    }
+}
@@ -0,0 +1,205 @@
+/*
+ * This file is part of Lolcate.
+ *
+ * Copyright © 2019 Nicolas Girard
+ *
+ * Lolcate is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Lolcate is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Lolcate.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Entries of the data files.
+//!
+//! Each line of a data file holds a path name, possibly followed by metadata
+//! fields. Since path names can't contain NUL bytes, each field is preceded
+//! by a NUL byte, and consists of a one-letter key, a colon and a value,
+//! e.g. `/home/user/photo\0m:image/jpeg\0s:52107`.
+
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+const MIME_KEY: char = 'm';
+const KIND_KEY: char = 'k';
+const SIZE_KEY: char = 's';
+const MTIME_KEY: char = 't';
+
+/// The kind of file a path name refers to.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Kind {
+    File,
+    Dir,
+    Symlink,
+    Other,
+}
+
+impl Kind {
+    pub fn from_entry(entry: &ignore::DirEntry) -> Option<Kind> {
+        let file_type = entry.file_type()?;
+        Some(if entry.path_is_symlink() {
+            Kind::Symlink
+        } else if file_type.is_file() {
+            Kind::File
+        } else if file_type.is_dir() {
+            Kind::Dir
+        } else {
+            Kind::Other
+        })
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Kind::File => "file",
+            Kind::Dir => "dir",
+            Kind::Symlink => "symlink",
+            Kind::Other => "other",
+        }
+    }
+
+    fn code(self) -> char {
+        match self {
+            Kind::File => 'f',
+            Kind::Dir => 'd',
+            Kind::Symlink => 'l',
+            Kind::Other => 'o',
+        }
+    }
+
+    fn from_code(code: &str) -> Option<Kind> {
+        match code {
+            "f" => Some(Kind::File),
+            "d" => Some(Kind::Dir),
+            "l" => Some(Kind::Symlink),
+            "o" => Some(Kind::Other),
+            _ => None,
+        }
+    }
+}
+
+/// The metadata stored along with a path name.
+#[derive(Debug, Default)]
+pub struct Metadata {
+    pub mime: Option<String>,
+    pub kind: Option<Kind>,
+    pub size: Option<u64>,
+    /// Modification time, in seconds since the Unix epoch
+    pub mtime: Option<i64>,
+}
+
+impl Metadata {
+    /// Reads the kind, size and modification time of the file of `entry`.
+    pub fn stat(&mut self, entry: &ignore::DirEntry) {
+        self.kind = Kind::from_entry(entry);
+        if let Ok(metadata) = entry.metadata() {
+            self.size = Some(metadata.len());
+            self.mtime = metadata.modified().ok().map(|mtime| match mtime.duration_since(UNIX_EPOCH) {
+                Ok(duration) => duration.as_secs() as i64,
+                Err(error) => -(error.duration().as_secs() as i64),
+            });
+        }
+    }
+}
+
+/// An entry of a data file.
+pub struct Entry<'a> {
+    pub path: &'a str,
+    fields: &'a str,
+}
+
+impl<'a> Entry<'a> {
+    pub fn parse(line: &'a str) -> Entry<'a> {
+        match line.find('\0') {
+            Some(pos) => Entry {
+                path: &line[..pos],
+                fields: &line[pos..],
+            },
+            None => Entry {
+                path: line,
+                fields: "",
+            },
+        }
+    }
+
+    fn field(&self, key: char) -> Option<&'a str> {
+        self.fields
+            .split('\0')
+            .filter_map(|f| f.strip_prefix(key))
+            .find_map(|f| f.strip_prefix(':'))
+    }
+
+    /// The metadata fields, including their leading NUL byte.
+    pub fn fields(&self) -> &'a str {
+        self.fields
+    }
+
+    /// The MIME type sniffed from the contents of the file, if any.
+    pub fn mime(&self) -> Option<&'a str> {
+        self.field(MIME_KEY)
+    }
+
+    pub fn kind(&self) -> Option<Kind> {
+        self.field(KIND_KEY).and_then(Kind::from_code)
+    }
+
+    pub fn size(&self) -> Option<u64> {
+        self.field(SIZE_KEY).and_then(|size| size.parse().ok())
+    }
+
+    pub fn mtime(&self) -> Option<i64> {
+        self.field(MTIME_KEY).and_then(|mtime| mtime.parse().ok())
+    }
+}
+
+/// Formats the line of a data file for `path`.
+pub fn format_line(path: &str, metadata: &Metadata) -> String {
+    let mut line = path.to_string();
+    if let Some(mime) = &metadata.mime {
+        line.push_str(&format!("\0{}:{}", MIME_KEY, mime));
+    }
+    if let Some(kind) = metadata.kind {
+        line.push_str(&format!("\0{}:{}", KIND_KEY, kind.code()));
+    }
+    if let Some(size) = metadata.size {
+        line.push_str(&format!("\0{}:{}", SIZE_KEY, size));
+    }
+    if let Some(mtime) = metadata.mtime {
+        line.push_str(&format!("\0{}:{}", MTIME_KEY, mtime));
+    }
+    line
+}
+
+/// Number of bytes read from each file to detect its MIME type.
+const SNIFF_LEN: usize = 8192;
+
+/// Detects the MIME type of a regular file from its first bytes.
+pub fn sniff_mime(path: &Path) -> Option<String> {
+    let mut buffer = Vec::with_capacity(SNIFF_LEN);
+    let file = fs::File::open(path).ok()?;
+    file.take(SNIFF_LEN as u64).read_to_end(&mut buffer).ok()?;
+    if buffer.is_empty() {
+        return Some("application/x-empty".to_string());
+    }
+    if let Some(kind) = infer::get(&buffer) {
+        return Some(kind.mime_type().to_string());
+    }
+    let text = !buffer.contains(&0)
+        && match std::str::from_utf8(&buffer) {
+            Ok(_) => true,
+            // The buffer may end in the middle of a character
+            Err(error) => error.error_len().is_none(),
+        };
+    match text {
+        true => Some("text/plain".to_string()),
+        false => Some("application/octet-stream".to_string()),
+    }
+}
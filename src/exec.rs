@@ -0,0 +1,406 @@
+/*
+ * This file is part of Lolcate.
+ *
+ * Copyright © 2019 Nicolas Girard
+ *
+ * Lolcate is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Lolcate is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Lolcate.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Execution of commands on the path names found by queries, using the
+//! placeholders of fd: `{}`, `{/}`, `{//}`, `{.}` and `{/.}`.
+
+use std::io::{self, Write};
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+/// Maximum size of the arguments and environment of a command run by
+/// `--exec-batch`, beyond which the path names are split into several
+/// batches.
+const MAX_BATCH_SIZE: usize = 128 * 1024;
+
+/// Size taken by an argument or environment variable when running a
+/// command: its bytes, the terminating NUL and the pointer to it.
+fn arg_size(arg: &str) -> usize {
+    arg.len() + 1 + std::mem::size_of::<usize>()
+}
+
+fn environment_size() -> usize {
+    std::env::vars_os()
+        .map(|(name, value)| name.len() + 1 + value.len() + 1 + std::mem::size_of::<usize>())
+        .sum()
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum Placeholder {
+    /// `{}`
+    Path,
+    /// `{/}`
+    Basename,
+    /// `{//}`
+    Parent,
+    /// `{.}`
+    NoExtension,
+    /// `{/.}`
+    BasenameNoExtension,
+}
+
+enum Part {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+/// The arguments of a command, with placeholders replaced by each path name.
+pub struct CommandTemplate {
+    args: Vec<Vec<Part>>,
+}
+
+impl CommandTemplate {
+    /// Parses the arguments given to `--exec` or `--exec-batch`. When none of
+    /// them contains a placeholder, `{}` is appended.
+    pub fn new<'a, I: IntoIterator<Item = &'a str>>(args: I) -> CommandTemplate {
+        let mut args: Vec<Vec<Part>> = args.into_iter().map(parse_arg).collect();
+        let has_placeholder = args
+            .iter()
+            .flatten()
+            .any(|part| matches!(part, Part::Placeholder(_)));
+        if !has_placeholder {
+            args.push(vec![Part::Placeholder(Placeholder::Path)]);
+        }
+        CommandTemplate { args }
+    }
+
+    fn render_arg(arg: &[Part], path: &str) -> String {
+        let mut out = String::new();
+        for part in arg {
+            match part {
+                Part::Literal(literal) => out.push_str(literal),
+                Part::Placeholder(placeholder) => out.push_str(&render(*placeholder, path)),
+            }
+        }
+        out
+    }
+
+    /// Builds the command to run on a single path name.
+    fn command(&self, path: &str) -> process::Command {
+        let mut args = self.args.iter().map(|arg| Self::render_arg(arg, path));
+        let mut command = process::Command::new(args.next().unwrap_or_default());
+        command.args(args);
+        command
+    }
+
+    fn has_placeholder(arg: &[Part]) -> bool {
+        arg.iter().any(|part| matches!(part, Part::Placeholder(_)))
+    }
+
+    /// Size of the arguments of a batch command which don't depend on the
+    /// path names.
+    fn fixed_size(&self) -> usize {
+        self.args
+            .iter()
+            .filter(|arg| !Self::has_placeholder(arg))
+            .map(|arg| arg_size(&Self::render_arg(arg, "")))
+            .sum()
+    }
+
+    /// Size of the arguments added to a batch command by the path name `path`.
+    fn path_size(&self, path: &str) -> usize {
+        self.args
+            .iter()
+            .filter(|arg| Self::has_placeholder(arg))
+            .map(|arg| arg_size(&Self::render_arg(arg, path)))
+            .sum()
+    }
+
+    /// Splits `paths` into batches whose commands have arguments of at most
+    /// `limit` bytes, unless a single path name exceeds it.
+    fn batches(&self, paths: &[String], limit: usize) -> Vec<std::ops::Range<usize>> {
+        let limit = limit.saturating_sub(self.fixed_size());
+        let mut batches = Vec::new();
+        let mut start = 0;
+        let mut size = 0;
+        for (i, path) in paths.iter().enumerate() {
+            let path_size = self.path_size(path);
+            if size + path_size > limit && i > start {
+                batches.push(start..i);
+                start = i;
+                size = 0;
+            }
+            size += path_size;
+        }
+        if start < paths.len() {
+            batches.push(start..paths.len());
+        }
+        batches
+    }
+
+    /// Builds the command to run on several path names at once: the
+    /// arguments holding placeholders are repeated for each of them.
+    fn batch_command(&self, paths: &[String]) -> process::Command {
+        let mut args = Vec::new();
+        for arg in &self.args {
+            match Self::has_placeholder(arg) {
+                true => args.extend(paths.iter().map(|path| Self::render_arg(arg, path))),
+                false => args.push(Self::render_arg(arg, "")),
+            }
+        }
+        let mut args = args.into_iter();
+        let mut command = process::Command::new(args.next().unwrap_or_default());
+        command.args(args);
+        command
+    }
+}
+
+fn parse_arg(arg: &str) -> Vec<Part> {
+    const PLACEHOLDERS: [(&str, Placeholder); 5] = [
+        ("{}", Placeholder::Path),
+        ("{/}", Placeholder::Basename),
+        ("{//}", Placeholder::Parent),
+        ("{.}", Placeholder::NoExtension),
+        ("{/.}", Placeholder::BasenameNoExtension),
+    ];
+    let mut parts = Vec::new();
+    let mut rest = arg;
+    'outer: while !rest.is_empty() {
+        for (pos, _) in rest.match_indices('{') {
+            for (text, placeholder) in &PLACEHOLDERS {
+                if rest[pos..].starts_with(text) {
+                    if pos > 0 {
+                        parts.push(Part::Literal(rest[..pos].to_string()));
+                    }
+                    parts.push(Part::Placeholder(*placeholder));
+                    rest = &rest[pos + text.len()..];
+                    continue 'outer;
+                }
+            }
+        }
+        parts.push(Part::Literal(rest.to_string()));
+        break;
+    }
+    parts
+}
+
+fn render(placeholder: Placeholder, path: &str) -> String {
+    let p = Path::new(path);
+    let basename = || {
+        p.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.to_string())
+    };
+    match placeholder {
+        Placeholder::Path => path.to_string(),
+        Placeholder::Basename => basename(),
+        Placeholder::Parent => match p.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_string_lossy().into_owned(),
+            _ => ".".to_string(),
+        },
+        Placeholder::NoExtension => p.with_extension("").to_string_lossy().into_owned(),
+        Placeholder::BasenameNoExtension => {
+            let basename = basename();
+            Path::new(&basename).with_extension("").to_string_lossy().into_owned()
+        }
+    }
+}
+
+/// Runs a command, reporting it if it can't be started. Returns whether it
+/// succeeded.
+fn run(mut command: process::Command) -> bool {
+    match command.status() {
+        Ok(status) => status.success(),
+        Err(error) => {
+            eprintln!("Cannot run {:?}: {}", command.get_program(), error);
+            false
+        }
+    }
+}
+
+/// Runs a command with its output captured, then writes the output at once
+/// so that the outputs of parallel commands don't mix.
+fn run_captured(mut command: process::Command) -> bool {
+    command.stdin(process::Stdio::null());
+    match command.output() {
+        Ok(output) => {
+            #[allow(unused_must_use)]
+            {
+                io::stdout().lock().write_all(&output.stdout);
+                io::stderr().lock().write_all(&output.stderr);
+            }
+            output.status.success()
+        }
+        Err(error) => {
+            eprintln!("Cannot run {:?}: {}", command.get_program(), error);
+            false
+        }
+    }
+}
+
+/// Runs a command on each path name in parallel, or on all of them at once.
+pub struct Executor {
+    template: Arc<CommandTemplate>,
+    batch: Option<Vec<String>>,
+    sender: Option<channel::Sender<String>>,
+    workers: Vec<thread::JoinHandle<()>>,
+    /// Whether all the commands succeeded so far
+    success: Arc<AtomicBool>,
+}
+
+impl Executor {
+    /// Runs a command on each path name, using `threads` threads.
+    pub fn new(template: CommandTemplate, threads: usize) -> Executor {
+        let template = Arc::new(template);
+        let success = Arc::new(AtomicBool::new(true));
+        let (sender, receiver) = channel::bounded::<String>(threads * 4);
+        let workers = (0..threads)
+            .map(|_| {
+                let template = Arc::clone(&template);
+                let success = Arc::clone(&success);
+                let receiver = receiver.clone();
+                thread::spawn(move || {
+                    for path in receiver {
+                        let command = template.command(&path);
+                        // A single command can use the terminal
+                        let succeeded = match threads {
+                            1 => run(command),
+                            _ => run_captured(command),
+                        };
+                        if !succeeded {
+                            success.store(false, Ordering::Relaxed);
+                        }
+                    }
+                })
+            })
+            .collect();
+        Executor {
+            template,
+            batch: None,
+            sender: Some(sender),
+            workers,
+            success,
+        }
+    }
+
+    /// Runs a command on all the path names at once.
+    pub fn batch(template: CommandTemplate) -> Executor {
+        Executor {
+            template: Arc::new(template),
+            batch: Some(Vec::new()),
+            sender: None,
+            workers: Vec::new(),
+            success: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    pub fn push(&mut self, path: &str) {
+        match (&mut self.batch, &self.sender) {
+            (Some(batch), _) => batch.push(path.to_string()),
+            (None, Some(sender)) => sender.send(path.to_string()).unwrap(),
+            (None, None) => {}
+        }
+    }
+
+    /// Waits for the commands to complete, and returns whether all of them
+    /// succeeded.
+    pub fn finish(mut self) -> bool {
+        if let Some(batch) = self.batch.take() {
+            let limit = MAX_BATCH_SIZE.saturating_sub(environment_size());
+            for range in self.template.batches(&batch, limit) {
+                if !run(self.template.batch_command(&batch[range])) {
+                    self.success.store(false, Ordering::Relaxed);
+                }
+            }
+        }
+        // Closing the channel stops the workers once they are done
+        self.sender.take();
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                self.success.store(false, Ordering::Relaxed);
+            }
+        }
+        self.success.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(paths: &[&str]) -> Vec<String> {
+        paths.iter().map(|path| path.to_string()).collect()
+    }
+
+    fn args(command: &process::Command) -> Vec<String> {
+        std::iter::once(command.get_program())
+            .chain(command.get_args())
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn placeholders() {
+        let path = "/music/album/song.flac";
+        assert_eq!(render(Placeholder::Path, path), path);
+        assert_eq!(render(Placeholder::Basename, path), "song.flac");
+        assert_eq!(render(Placeholder::Parent, path), "/music/album");
+        assert_eq!(render(Placeholder::NoExtension, path), "/music/album/song");
+        assert_eq!(render(Placeholder::BasenameNoExtension, path), "song");
+        assert_eq!(render(Placeholder::Parent, "song"), ".");
+    }
+
+    #[test]
+    fn command_renders_each_argument() {
+        let template = CommandTemplate::new(["convert", "{}", "{//}/{/.}.png", "{x}"]);
+        assert_eq!(
+            args(&template.command("/img/a.jpg")),
+            ["convert", "/img/a.jpg", "/img/a.png", "{x}"]
+        );
+    }
+
+    #[test]
+    fn path_is_appended_without_placeholders() {
+        let template = CommandTemplate::new(["ls", "-l"]);
+        assert_eq!(args(&template.command("/a b")), ["ls", "-l", "/a b"]);
+    }
+
+    #[test]
+    fn batch_command_repeats_placeholder_arguments() {
+        let template = CommandTemplate::new(["tar", "czf", "out.tgz", "{}"]);
+        assert_eq!(
+            args(&template.batch_command(&paths(&["/a", "/b"]))),
+            ["tar", "czf", "out.tgz", "/a", "/b"]
+        );
+        let template = CommandTemplate::new(["echo", "{/}", "--"]);
+        assert_eq!(
+            args(&template.batch_command(&paths(&["/x/a", "/y/b"]))),
+            ["echo", "a", "b", "--"]
+        );
+    }
+
+    #[test]
+    fn batches_measure_the_rendered_arguments() {
+        let ptr = std::mem::size_of::<usize>();
+        // "cp", "--" and "dest", then "/a/bN" and "bN.bak" for each path name
+        let template = CommandTemplate::new(["cp", "--", "{}", "{/}.bak", "dest"]);
+        let fixed = (2 + 1 + ptr) + (2 + 1 + ptr) + (4 + 1 + ptr);
+        let per_path = (5 + 1 + ptr) + (6 + 1 + ptr);
+        let all = paths(&["/a/b1", "/a/b2", "/a/b3"]);
+        assert_eq!(template.batches(&all, fixed + 3 * per_path), vec![0..3]);
+        assert_eq!(template.batches(&all, fixed + 3 * per_path - 1), vec![0..2, 2..3]);
+        assert_eq!(template.batches(&all, fixed + per_path), vec![0..1, 1..2, 2..3]);
+        // A path name exceeding the limit still gets a batch of its own
+        assert_eq!(template.batches(&all, 0), vec![0..1, 1..2, 2..3]);
+        assert!(template.batches(&[], 0).is_empty());
+    }
+}
@@ -0,0 +1,185 @@
+/*
+ * This file is part of Lolcate.
+ *
+ * Copyright © 2019 Nicolas Girard
+ *
+ * Lolcate is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Lolcate is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Lolcate.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! History of the path names opened from query results, used to rank them
+//! by frecency, i.e. frequency and recency, like zoxide does.
+//!
+//! Each line of the history file holds the number of visits of a path name,
+//! the time of its last visit in seconds since the Unix epoch, and the path
+//! name, separated by tabs. The numbers of visits decay as the history
+//! grows, so that old path names are eventually forgotten.
+//!
+//! The history belongs to the user, and is kept in their data directory
+//! even when the system-wide databases are queried.
+
+use crate::user_data_path;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Total number of visits above which they all decay.
+const MAX_VISITS: f64 = 10000.0;
+
+const HOUR: i64 = 3600;
+const DAY: i64 = 24 * HOUR;
+const WEEK: i64 = 7 * DAY;
+
+fn history_fn() -> PathBuf {
+    user_data_path().join("history")
+}
+
+/// Takes an exclusive lock on the history, held until the returned file is
+/// closed, so that concurrent queries don't lose each other's visits.
+fn lock(dir: &Path) -> io::Result<fs::File> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir.join("history.lock"))?;
+    #[cfg(not(windows))]
+    {
+        use std::os::unix::io::AsRawFd;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(file)
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs() as i64)
+}
+
+struct Visits {
+    count: f64,
+    last: i64,
+}
+
+impl Visits {
+    fn frecency(&self, now: i64) -> f64 {
+        let age = now - self.last;
+        let weight = if age < HOUR {
+            4.0
+        } else if age < DAY {
+            2.0
+        } else if age < WEEK {
+            0.5
+        } else {
+            0.25
+        };
+        self.count * weight
+    }
+}
+
+#[derive(Default)]
+pub struct History {
+    paths: HashMap<String, Visits>,
+}
+
+impl History {
+    /// Reads the history file, if any. Malformed lines are ignored.
+    pub fn load() -> io::Result<History> {
+        let contents = match fs::read_to_string(history_fn()) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(error) => return Err(error),
+        };
+        let paths = contents
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.splitn(3, '\t');
+                let count = fields.next()?.parse().ok()?;
+                let last = fields.next()?.parse().ok()?;
+                Some((fields.next()?.to_string(), Visits { count, last }))
+            })
+            .collect();
+        Ok(History { paths })
+    }
+
+    fn visit(&mut self, path: &str, now: i64) {
+        let visits = self.paths.entry(path.to_string()).or_insert(Visits {
+            count: 0.0,
+            last: now,
+        });
+        visits.count += 1.0;
+        visits.last = now;
+    }
+
+    /// Makes the numbers of visits decay when their total grows too large,
+    /// forgetting the path names left with less than one.
+    fn age(&mut self) {
+        let total: f64 = self.paths.values().map(|visits| visits.count).sum();
+        if total > MAX_VISITS {
+            let factor = 0.9 * MAX_VISITS / total;
+            self.paths.retain(|_, visits| {
+                visits.count *= factor;
+                visits.count >= 1.0
+            });
+        }
+    }
+
+    /// Writes the history file, replacing it at once so that concurrent
+    /// queries never read a partial one.
+    fn save(&self) -> io::Result<()> {
+        let history_fn = history_fn();
+        let dir = history_fn.parent().unwrap();
+        let mut file = tempfile::NamedTempFile::new_in(dir)?;
+        {
+            let mut writer = io::BufWriter::new(&mut file);
+            for (path, visits) in &self.paths {
+                writeln!(writer, "{}\t{}\t{}", visits.count, visits.last, path)?;
+            }
+            writer.flush()?;
+        }
+        file.persist(history_fn).map_err(|error| error.error)?;
+        Ok(())
+    }
+
+    /// Returns the frecency scores of the path names of the history.
+    pub fn scores(&self) -> HashMap<String, f64> {
+        let now = now();
+        self.paths
+            .iter()
+            .map(|(path, visits)| (path.clone(), visits.frecency(now)))
+            .collect()
+    }
+}
+
+/// Records a visit of each of `paths`.
+pub fn record<S: AsRef<str>>(paths: &[S]) -> io::Result<()> {
+    if paths.is_empty() {
+        return Ok(());
+    }
+    let history_fn = history_fn();
+    let dir = history_fn.parent().unwrap();
+    fs::create_dir_all(dir)?;
+    let _lock = lock(dir)?;
+    let mut history = History::load()?;
+    let now = now();
+    // Path names can't hold newlines in data files either
+    for path in paths.iter().filter(|path| !path.as_ref().contains('\n')) {
+        history.visit(path.as_ref(), now);
+    }
+    history.age();
+    history.save()
+}
@@ -20,37 +20,85 @@
 use crate::config::read_toml_file;
 extern crate crossbeam_channel as channel;
 use bstr::io::BufReadExt;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use lazy_static::lazy_static;
 use lz4::EncoderBuilder;
 use std::collections::HashMap;
+use std::env;
 use std::fs;
 use std::io;
-use std::io::Write;
-use std::path::PathBuf;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
 use std::process;
 use std::str;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
 use std::thread;
+use std::time::Instant;
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 
 mod cli;
 mod config;
+mod daemon;
+mod entry;
+mod exec;
+mod history;
+mod mounts;
+mod output;
+mod picker;
+mod serve;
+mod sort;
+mod stats;
+mod template;
+mod types;
+mod update_log;
+mod visibility;
 
-use regex::{Regex, RegexBuilder};
+use regex::{Regex, RegexBuilder, RegexSet};
 
-static GLOBAL_CONFIG_TEMPLATE: &str = r#"[types]
-# Definition of custom path name types
-# Examples:
-img = ".*\\.(jp.?g|png|gif|JP.?G)$"
-video = ".*\\.(flv|mp4|mp.?g|avi|wmv|mkv|3gp|m4v|asf|webm)$"
-doc = ".*\\.(pdf|chm|epub|djvu?|mobi|azw3|odf|ods|md|tex|txt|adoc)$"
-audio = ".*\\.(mp3|m4a|flac|ogg)$"
+static GLOBAL_CONFIG_TEMPLATE: &str = r#"# Default options, overridden by the command line:
+# [defaults]
+# database = "default"
+# ignore_case = true
+# color = "auto"
+# limit = 100
+# format = "plain"
+# history = false
+# stale_after = "7d"
+
+# Groups of databases, which can be given to --db like databases:
+# [groups]
+# work = ["src", "docs", "wiki"]
+
+[types]
+# Definition of custom path name types, either as:
+# - a regex: name = ".*\\.(ext1|ext2)$"
+# - a list of other types: name = ["type1", "type2"]
+# - a table combining any of extensions, globs, a regex and other types:
+#   name = { extensions = ["ext1", "ext2"], globs = ["Makefile"], types = ["type1"] }
+#   MIME types can be used in tables as well, for databases which detect them:
+#   name = { mime = ["image/*"] }
+# Examples, also matching files without an extension by their MIME type:
+img = { regex = ".*\\.(jp.?g|png|gif|JP.?G)$", mime = ["image/*"] }
+video = { regex = ".*\\.(flv|mp4|mp.?g|avi|wmv|mkv|3gp|m4v|asf|webm)$", mime = ["video/*"] }
+doc = { regex = ".*\\.(pdf|chm|epub|djvu?|mobi|azw3|odf|ods|md|tex|txt|adoc)$", mime = ["application/pdf", "application/epub+zip", "application/x-mobipocket-ebook", "application/vnd.oasis.opendocument.*", "image/vnd.djvu"] }
+audio = { regex = ".*\\.(mp3|m4a|flac|ogg)$", mime = ["audio/*"] }
+media = ["img", "video", "audio"]
 
 "#;
 
 static PROJECT_CONFIG_TEMPLATE: &str = r#"
 description = ""
 
+# Set to true to leave this database out of --all.
+# It can still be queried or updated using --db.
+# hide_from_all = false
+
 # Directories to index.
+# Environment variables ($VAR or ${VAR}) and glob patterns are expanded.
+# A directory can also be given as a table overriding the options below,
+# e.g. { path = "~/src", gitignore = true, max_depth = 4, ignore = ["*.o"] }
 dirs = [
   # "~/first/dir",
   # "/second/dir"
@@ -69,6 +117,53 @@ ignore_hidden = false
 # Set to true to read .gitignore files and ignore matching files
 gitignore = false
 
+# Limit the depth of the indexed path names below each directory
+# max_depth = 10
+# min_depth = 1
+
+# Set to true to stay on the filesystem of each directory
+# same_file_system = true
+
+# Skip mount points of these filesystem types (Linux only)
+# prune_fs_types = ["nfs", "tmpfs", "proc"]
+
+# What to do when a directory is missing: "fail", "skip" or "keep_previous"
+# on_missing = "fail"
+
+# Set to true to detect the MIME type of the files from their contents,
+# so that they can be queried using --mime or MIME-based file types
+# detect_mime = false
+
+# Set to true to store the kind, size and modification time of the entries,
+# so that they can be shown by --json or --format
+# store_metadata = false
+
+# Set to true to write the entries sorted by path name, so that queries
+# return them in a reproducible order. The entries are sorted in memory.
+# sort_entries = false
+
+# Regular expressions matched against the full path names. When set,
+# only the path names matching one of include_regex are indexed, and those
+# matching one of exclude_regex are left out. Directories are descended
+# into anyway.
+# include_regex = ['\.(flac|mp3)$']
+# exclude_regex = ['/tmp/']
+
+"#;
+
+static PROJECT_INLINE_IGNORE_TEMPLATE: &str = r#"# Dirs / files to ignore.
+# Use the same syntax as gitignore(5).
+# Common patterns:
+ignore = [
+  # ".git",
+  # "*~"
+]
+
+"#;
+
+static PROJECT_TYPES_TEMPLATE: &str = r#"# File types specific to this database, overriding the global ones.
+# [types]
+# rust = { extensions = ["rs"], globs = ["Cargo.toml"] }
 "#;
 
 static PROJECT_IGNORE_TEMPLATE: &str = r#"# Dirs / files to ignore.
@@ -79,28 +174,66 @@ static PROJECT_IGNORE_TEMPLATE: &str = r#"# Dirs / files to ignore.
 # *~
 "#;
 
+/// Whether the configuration and data files are the user's own, or those of
+/// the system-wide databases.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Scope {
+    User,
+    System,
+}
+
+static SCOPE: OnceLock<Scope> = OnceLock::new();
+
+static SYSTEM_CONFIG_PATH: &str = "/etc/lolcate";
+static SYSTEM_DATA_PATH: &str = "/var/lib/lolcate";
+
+pub fn scope() -> Scope {
+    *SCOPE.get().unwrap_or(&Scope::User)
+}
+
 pub fn lolcate_config_path() -> PathBuf {
+    if scope() == Scope::System {
+        return PathBuf::from(SYSTEM_CONFIG_PATH);
+    }
+    if let Some(path) = env::var_os("LOLCATE_CONFIG_DIR").filter(|p| !p.is_empty()) {
+        return PathBuf::from(path);
+    }
     let mut path = dirs::config_dir().unwrap();
     path.push("lolcate");
     path
 }
 
 pub fn lolcate_data_path() -> PathBuf {
+    if scope() == Scope::System {
+        return PathBuf::from(SYSTEM_DATA_PATH);
+    }
+    user_data_path()
+}
+
+/// Returns the data directory of the current user, even when the system-wide
+/// databases are used.
+pub fn user_data_path() -> PathBuf {
+    if let Some(path) = env::var_os("LOLCATE_DATA_DIR").filter(|p| !p.is_empty()) {
+        return PathBuf::from(path);
+    }
     let mut path = dirs::data_local_dir().unwrap();
     path.push("lolcate");
     path
 }
 
-fn get_db_config(toml_file: &PathBuf) -> config::Config {
+fn read_db_config(toml_file: &Path) -> Result<config::Config, String> {
     let mut buffer = String::new();
-    let config: config::Config = match read_toml_file(&toml_file, &mut buffer) {
+    read_toml_file(toml_file, &mut buffer).map_err(|error| format!("Invalid TOML: {}", error))
+}
+
+fn get_db_config(toml_file: &Path) -> config::Config {
+    match read_db_config(toml_file) {
         Ok(config) => config,
         Err(error) => {
-            eprintln!("Invalid TOML: {}", error);
+            eprintln!("{}", error);
             process::exit(1);
         }
-    };
-    config
+    }
 }
 
 fn create_global_config_if_needed() -> std::io::Result<()> {
@@ -113,46 +246,105 @@ fn create_global_config_if_needed() -> std::io::Result<()> {
     Ok(())
 }
 
-fn get_global_config(toml_file: &PathBuf) -> config::GlobalConfig {
+fn read_global_config(toml_file: &Path) -> Result<config::GlobalConfig, String> {
+    if !toml_file.exists() {
+        // Users don't create the global configuration of the system scope
+        return Ok(config::GlobalConfig::default());
+    }
     let mut buffer = String::new();
-    let config: config::GlobalConfig = match read_toml_file(&toml_file, &mut buffer) {
+    read_toml_file(toml_file, &mut buffer).map_err(|error| format!("Invalid TOML: {}", error))
+}
+
+fn get_global_config(toml_file: &Path) -> config::GlobalConfig {
+    match read_global_config(toml_file) {
         Ok(config) => config,
         Err(error) => {
-            eprintln!("Invalid TOML: {}", error);
+            eprintln!("{}", error);
             process::exit(1);
         }
-    };
-    config
+    }
 }
 
-fn get_types_map() -> HashMap<String, String> {
-    let _fn = global_config_fn();
-    let _config = get_global_config(&_fn);
-    _config.types
+/// Returns the file types available when querying a database, i.e. the
+/// global ones overridden by those of the database.
+fn db_types_map(
+    global_types: &HashMap<String, config::TypeDef>,
+    config: Option<&config::Config>,
+) -> HashMap<String, config::TypeDef> {
+    let mut types = global_types.clone();
+    if let Some(config) = config {
+        types.extend(config.types.clone());
+    }
+    types
 }
 
-fn check_db_config(config: &config::Config, toml_file: &PathBuf) {
+/// Checks the configuration of a database and resolves the directories to
+/// index. Returns these directories, along with the missing ones whose
+/// entries must be carried over from the previous index.
+fn check_db_config(
+    config: &config::Config,
+    toml_file: &PathBuf,
+    mount_point: Option<&Path>,
+) -> (Vec<config::Root>, Vec<config::Root>) {
     // Check config
-    if config.dirs.len() == 0 {
+    if config.dirs.is_empty() {
         eprintln!(
             "Please edit file {:?} and add at least a directory to scan.",
             toml_file
         );
         process::exit(1);
     }
-    for dir in &config.dirs {
-        if !dir.exists() {
-            eprintln!("The specified dir {} doesn't exist.", dir.display());
+    let roots = match config.roots(mount_point) {
+        Ok(roots) => roots,
+        Err(error) => {
+            eprintln!("{}", error);
             process::exit(1);
         }
-        if !dir.is_dir() {
+    };
+    if let Some(mount_point) = mount_point {
+        if let Some(root) = roots.iter().find(|r| !r.path.starts_with(mount_point)) {
             eprintln!(
-                "The specified path {} is not a directory or cannot be accessed.",
-                dir.display()
+                "The specified dir {} is outside of the volume mounted at {}.",
+                root.path.display(),
+                mount_point.display()
             );
             process::exit(1);
         }
     }
+    let mut kept = Vec::new();
+    let mut available = Vec::new();
+    for root in roots {
+        if root.unexpanded {
+            kept.push(root);
+            continue;
+        }
+        let dir = &root.path;
+        let problem = if !dir.exists() {
+            format!("The specified dir {} doesn't exist.", dir.display())
+        } else if !dir.is_dir() {
+            format!(
+                "The specified path {} is not a directory or cannot be accessed.",
+                dir.display()
+            )
+        } else {
+            available.push(root);
+            continue;
+        };
+        match root.on_missing {
+            config::OnMissing::Fail => {
+                eprintln!("{}", problem);
+                process::exit(1);
+            }
+            config::OnMissing::Skip => {
+                eprintln!("{} Skipping it.", problem);
+            }
+            config::OnMissing::KeepPrevious => {
+                eprintln!("{} Keeping its previous entries.", problem);
+                kept.push(root);
+            }
+        }
+    }
+    (available, kept)
 }
 
 fn global_config_fn() -> PathBuf {
@@ -182,26 +374,217 @@ fn ignores_fn(db_name: &str) -> PathBuf {
     _fn
 }
 
-fn create_database(db_name: &str) -> std::io::Result<()> {
+fn db_config_dir(db_name: &str) -> PathBuf {
+    let mut _dir = lolcate_config_path();
+    _dir.push(db_name);
+    _dir
+}
+
+fn db_data_dir(db_name: &str) -> PathBuf {
+    let mut _dir = lolcate_data_path();
+    _dir.push(db_name);
+    _dir
+}
+
+/// Returns an error unless `db_name` can be used as a file name.
+fn validate_db_name(db_name: &str) -> Result<(), String> {
+    if db_name.is_empty()
+        || db_name == "."
+        || db_name == ".."
+        || db_name.contains(['/', ',', std::path::MAIN_SEPARATOR])
+    {
+        return Err(format!("Invalid database name: {:?}", db_name));
+    }
+    Ok(())
+}
+
+fn check_db_name(db_name: &str) {
+    if let Err(error) = validate_db_name(db_name) {
+        eprintln!("{}", error);
+        process::exit(1);
+    }
+}
+
+/// Exits if `db_name` is the name of a group of databases.
+fn check_not_a_group(db_name: &str) {
+    let global_config = get_global_config(&global_config_fn());
+    if global_config.groups.contains_key(db_name) {
+        eprintln!(
+            "{} is the name of a group of databases in {}.",
+            db_name,
+            global_config_fn().display()
+        );
+        process::exit(1);
+    }
+}
+
+fn create_database(db_name: &str, single_file: bool) -> std::io::Result<()> {
+    check_db_name(db_name);
+    create_global_config_if_needed()?;
+    check_not_a_group(db_name);
     let mut db_dir = lolcate_data_path();
     db_dir.push(db_name);
     if db_dir.exists() {
         eprintln!("Database {} already exists", &db_name);
         process::exit(1);
     }
-    let config_fn = config_fn(&db_name);
+    let config_fn = config_fn(db_name);
     fs::create_dir_all(config_fn.parent().unwrap())?;
     let mut f = fs::File::create(&config_fn)?;
     f.write_all(PROJECT_CONFIG_TEMPLATE.as_bytes())?;
 
-    let ignores_fn = ignores_fn(&db_name);
+    if single_file {
+        f.write_all(PROJECT_INLINE_IGNORE_TEMPLATE.as_bytes())?;
+    }
+    f.write_all(PROJECT_TYPES_TEMPLATE.as_bytes())?;
+
+    if single_file {
+        println!("Created database '{}'.\nPlease edit:", db_name);
+        println!("- the configuration file: {}", config_fn.display());
+        return Ok(());
+    }
+
+    let ignores_fn = ignores_fn(db_name);
     f = fs::File::create(&ignores_fn)?;
     f.write_all(PROJECT_IGNORE_TEMPLATE.as_bytes())?;
 
     println!("Created database '{}'.\nPlease edit:", db_name);
     println!("- the configuration file: {}", config_fn.display());
     println!("- the ignores file:       {}", ignores_fn.display());
-    process::exit(0);
+    Ok(())
+}
+
+fn confirm(prompt: &str) -> std::io::Result<bool> {
+    print!("{} [y/N] ", prompt);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes" | "Yes"))
+}
+
+fn remove_database(db_name: &str, assume_yes: bool) -> std::io::Result<()> {
+    check_db_name(db_name);
+    let dirs: Vec<PathBuf> = vec![db_config_dir(db_name), db_data_dir(db_name)]
+        .into_iter()
+        .filter(|dir| dir.exists())
+        .collect();
+    if dirs.is_empty() {
+        eprintln!("Database {} doesn't exist.", &db_name);
+        process::exit(1);
+    }
+    if !assume_yes {
+        println!("The following directories will be deleted:");
+        for dir in &dirs {
+            println!("- {}", dir.display());
+        }
+        if !confirm(&format!("Remove database '{}'?", db_name))? {
+            println!("Aborted.");
+            return Ok(());
+        }
+    }
+    for dir in &dirs {
+        fs::remove_dir_all(dir)?;
+    }
+    println!("Removed database '{}'.", db_name);
+    Ok(())
+}
+
+fn rename_database(old_name: &str, new_name: &str) -> std::io::Result<()> {
+    check_db_name(old_name);
+    check_db_name(new_name);
+    check_not_a_group(new_name);
+    let moves: Vec<(PathBuf, PathBuf)> = vec![
+        (db_config_dir(old_name), db_config_dir(new_name)),
+        (db_data_dir(old_name), db_data_dir(new_name)),
+    ];
+    if !moves[0].0.exists() && !moves[1].0.exists() {
+        eprintln!("Database {} doesn't exist.", &old_name);
+        process::exit(1);
+    }
+    // Refuse to overwrite anything before moving the first directory
+    for (_, to) in &moves {
+        if to.exists() {
+            eprintln!(
+                "Cannot rename database {} to {}: {} already exists.",
+                &old_name,
+                &new_name,
+                to.display()
+            );
+            process::exit(1);
+        }
+    }
+    let mut moved: Vec<&(PathBuf, PathBuf)> = Vec::new();
+    for paths in &moves {
+        let (from, to) = paths;
+        if !from.exists() {
+            continue;
+        }
+        if let Err(e) = fs::rename(from, to) {
+            // Move back what was already renamed so the database stays whole
+            for (from, to) in moved.iter().rev() {
+                if let Err(e) = fs::rename(to, from) {
+                    eprintln!(
+                        "Could not move {} back to {}: {}",
+                        to.display(),
+                        from.display(),
+                        e
+                    );
+                }
+            }
+            return Err(e);
+        }
+        moved.push(paths);
+    }
+    println!("Renamed database '{}' to '{}'.", old_name, new_name);
+    Ok(())
+}
+
+fn editor_command() -> String {
+    env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| {
+            if cfg!(windows) {
+                "notepad".to_string()
+            } else {
+                "vi".to_string()
+            }
+        })
+}
+
+fn edit_config(args: &clap::ArgMatches, defaults: &config::Defaults) -> std::io::Result<()> {
+    let db_name = &database_name(args, defaults);
+    let _fn = if args.is_present("global") {
+        global_config_fn()
+    } else if args.is_present("ignores") {
+        ignores_fn(db_name)
+    } else {
+        config_fn(db_name)
+    };
+    if !args.is_present("global") && !config_fn(db_name).exists() {
+        eprintln!(
+            "Config file not found for database {}.\nPerhaps you forgot to run lolcate create --db {} ?",
+            &db_name, &db_name
+        );
+        process::exit(1);
+    }
+    let editor = editor_command();
+    let mut editor_args = editor.split_whitespace();
+    let program = match editor_args.next() {
+        Some(program) => program,
+        None => {
+            eprintln!("No editor configured. Please set $VISUAL or $EDITOR.");
+            process::exit(1);
+        }
+    };
+    let status = process::Command::new(program)
+        .args(editor_args)
+        .arg(&_fn)
+        .status()?;
+    if !status.success() {
+        eprintln!("Editor {} exited with {}", program, status);
+        process::exit(1);
+    }
+    Ok(())
 }
 
 fn database_names(path: PathBuf) -> Vec<String> {
@@ -215,12 +598,29 @@ fn database_names(path: PathBuf) -> Vec<String> {
     _dbs
 }
 
-fn info_databases() -> std::io::Result<()> {
-    let mut db_data: Vec<(String, String, String, String, String)> = Vec::new();
+fn info_databases(color: ColorChoice) -> std::io::Result<()> {
+    let mut db_data: Vec<(String, Vec<(&str, String)>)> = Vec::new();
+    let global_config = get_global_config(&global_config_fn());
+    let global_types = global_config.types;
+    let stale_after = global_config.defaults.stale_after.as_ref().map(|interval| {
+        match config::parse_interval(interval) {
+            Ok(seconds) => (interval, seconds),
+            Err(error) => {
+                eprintln!("Invalid stale_after: {}", error);
+                process::exit(1);
+            }
+        }
+    });
+    let now = chrono::Local::now().timestamp();
+    let mut stale_databases = Vec::new();
+    let mut types_data: Vec<(String, String, String)> = global_types
+        .iter()
+        .map(|(name, def)| (name.clone(), def.to_string(), "global".to_string()))
+        .collect();
     let walker = walkdir::WalkDir::new(lolcate_config_path())
         .min_depth(1)
         .into_iter();
-    let mut stdout = StandardStream::stdout(ColorChoice::Always);
+    let mut stdout = StandardStream::stdout(color);
     let mut section_spec = ColorSpec::new();
     section_spec.set_fg(Some(Color::Cyan));
     let mut entry_spec = ColorSpec::new();
@@ -231,18 +631,62 @@ fn info_databases() -> std::io::Result<()> {
     writeln!(&mut stdout, "  {}\n", global_config_fn().display())?;
     for entry in walker.filter_entry(|e| e.file_type().is_dir()) {
         if let Some(db_name) = entry.unwrap().file_name().to_str() {
-            let config_fn = config_fn(&db_name);
+            let config_fn = config_fn(db_name);
             let config = get_db_config(&config_fn);
-            let description = config.description;
             let mut db_fn = lolcate_data_path();
             db_fn.push(db_name);
-            db_data.push((
-                db_name.to_string(),
-                description.to_string(),
-                config_fn.display().to_string(),
-                ignores_fn(&db_name).display().to_string(),
-                db_fn.display().to_string(),
+            let mut fields = vec![
+                ("Description", config.description.to_string()),
+                ("Config file", config_fn.display().to_string()),
+                (
+                    "Ignores file",
+                    match ignores_fn(db_name) {
+                        _fn if _fn.exists() => _fn.display().to_string(),
+                        _ => "(none)".to_string(),
+                    },
+                ),
+                ("Data file", db_fn.display().to_string()),
+            ];
+            if config.hide_from_all {
+                fields.push(("In --all", "no".to_string()));
+            }
+            if let Some(volume) = &config.volume {
+                fields.push((
+                    "Volume",
+                    match mounts::volume_mount_point(volume) {
+                        Some(mount_point) => {
+                            format!("{} (mounted at {})", volume, mount_point.display())
+                        }
+                        None => format!("{} (not mounted)", volume),
+                    },
+                ));
+            }
+            let last_update = update_log::last_update_time(db_name);
+            let stale = stale_after
+                .is_some_and(|(_, seconds)| update_log::is_stale(last_update, seconds, now));
+            fields.push((
+                "Last update",
+                match last_update {
+                    Some(time) => format!(
+                        "{} ({}{})",
+                        update_log::format_time(time),
+                        update_log::format_age(time, now),
+                        if stale { ", stale" } else { "" }
+                    ),
+                    None => "never".to_string(),
+                },
             ));
+            if stale {
+                stale_databases.push((db_name.to_string(), last_update.is_none()));
+            }
+            for (name, def) in &config.types {
+                let source = match global_types.contains_key(name) {
+                    true => format!("database {}, overrides global", db_name),
+                    false => format!("database {}", db_name),
+                };
+                types_data.push((name.clone(), def.to_string(), source));
+            }
+            db_data.push((db_name.to_string(), fields));
         }
     }
     stdout.set_color(&section_spec)?;
@@ -253,57 +697,234 @@ fn info_databases() -> std::io::Result<()> {
         _ => {
             writeln!(&mut stdout, "Databases:")?;
             stdout.reset()?;
-            for (name, desc, config, ignores, db_fn) in db_data {
+            for (name, fields) in db_data {
                 stdout.set_color(&entry_spec)?;
                 writeln!(&mut stdout, "  {}", name)?;
                 stdout.reset()?;
-                println!("    Description:  {}", desc);
-                println!("    Config file:  {}", config);
-                println!("    Ignores file: {}", ignores);
-                println!("    Data file:    {}", db_fn);
+                for (label, value) in fields {
+                    println!("    {:<14}{}", format!("{}:", label), value);
+                }
             }
         }
     };
-    let tm = get_types_map();
+    if !global_config.groups.is_empty() {
+        stdout.set_color(&section_spec)?;
+        println!();
+        writeln!(&mut stdout, "Groups:")?;
+        stdout.reset()?;
+        let mut groups: Vec<_> = global_config.groups.iter().collect();
+        groups.sort();
+        for (name, members) in groups {
+            stdout.set_color(&entry_spec)?;
+            write!(&mut stdout, "  {}", name)?;
+            stdout.reset()?;
+            println!(": {}", members.join(", "));
+        }
+    }
     stdout.set_color(&section_spec)?;
     println!();
-    match tm.len() {
+    match types_data.len() {
         0 => {
             writeln!(&mut stdout, "No file types found.")?;
         }
         _ => {
             writeln!(&mut stdout, "File types:")?;
             stdout.reset()?;
-            for (name, glob) in tm {
+            types_data.sort();
+            for (name, glob, source) in types_data {
                 stdout.set_color(&entry_spec)?;
                 write!(&mut stdout, "  {}", name)?;
                 stdout.reset()?;
-                println!(": {}", glob);
+                println!(": {} ({})", glob, source);
             }
         }
     };
     stdout.reset()?;
     println!();
+    if let Some((interval, _)) = stale_after {
+        for (db_name, never) in stale_databases {
+            match never {
+                true => eprintln!("Warning: database {} has never been updated.", db_name),
+                false => eprintln!(
+                    "Warning: database {} hasn't been updated for more than {}.",
+                    db_name, interval
+                ),
+            }
+        }
+    }
     Ok(())
 }
 
-pub fn walker(config: &config::Config, database: &str) -> ignore::WalkParallel {
-    let paths = &config.dirs;
-    let mut wd = ignore::WalkBuilder::new(&paths[0]);
-    wd.hidden(config.ignore_hidden) // Whether to ignore hidden files
+/// Escapes the glob metacharacters of `path`, for use in an ignore rule.
+fn escape_glob(path: &Path) -> String {
+    let mut escaped = String::new();
+    for c in path.to_string_lossy().chars() {
+        if "\\*?[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Anchors `rule` at `root`. As in .gitignore files, rules containing a
+/// slash other than a trailing one match path names relative to `root`,
+/// while the other ones match file names anywhere below it.
+fn anchor_rule(root: &Path, rule: &str) -> String {
+    let (negation, pattern) = match rule.strip_prefix('!') {
+        Some(pattern) => ("!", pattern),
+        None => ("", rule),
+    };
+    if pattern.starts_with('#') || !pattern.trim_end_matches('/').contains('/') {
+        return rule.to_string();
+    }
+    format!(
+        "{}{}/{}",
+        negation,
+        escape_glob(root),
+        pattern.trim_start_matches('/')
+    )
+}
+
+/// Compiles the ignore rules applying to `root`: those of the ignores file
+/// of `db_name`, then the inline ones of its configuration file, then those
+/// of `root` itself, later rules taking precedence over earlier ones.
+fn ignore_matcher(root: &config::Root, config: &config::Config, db_name: &str) -> Gitignore {
+    let mut builder = GitignoreBuilder::new(db_config_dir(db_name));
+    let ignores_fn = ignores_fn(db_name);
+    if ignores_fn.exists() {
+        if let Some(error) = builder.add(&ignores_fn) {
+            eprintln!("Invalid ignores file {}: {}", ignores_fn.display(), error);
+            process::exit(1);
+        }
+    }
+    let root_rules = root.ignore.iter().map(|rule| anchor_rule(&root.path, rule));
+    let mut valid = true;
+    for rule in config.ignore.iter().cloned().chain(root_rules) {
+        if let Err(error) = builder.add_line(None, &rule) {
+            eprintln!("Invalid ignore rule in {}: {}", config_fn(db_name).display(), error);
+            valid = false;
+        }
+    }
+    if !valid {
+        process::exit(1);
+    }
+    match builder.build() {
+        Ok(matcher) => matcher,
+        Err(error) => {
+            eprintln!("Invalid ignore rules for {}: {}", db_name, error);
+            process::exit(1);
+        }
+    }
+}
+
+/// Whether `path` is ignored by `matcher`. Path names are matched relative
+/// to the filesystem root, so that anchored rules hold absolute path names.
+fn is_ignored(matcher: &Gitignore, path: &Path, is_dir: bool) -> bool {
+    let path = path.strip_prefix("/").unwrap_or(path);
+    matcher.matched(path, is_dir).is_ignore()
+}
+
+/// Compiles the `include_regex` or `exclude_regex` patterns of a database.
+fn path_regex_set(patterns: &[String], toml_file: &Path) -> Option<RegexSet> {
+    if patterns.is_empty() {
+        return None;
+    }
+    match RegexSet::new(patterns) {
+        Ok(set) => Some(set),
+        Err(error) => {
+            eprintln!("Invalid regex in {}: {}", toml_file.display(), error);
+            process::exit(1);
+        }
+    }
+}
+
+/// Number of threads walking the directories of each root.
+const WALKER_THREADS: usize = 4;
+
+pub fn walker(root: &config::Root) -> ignore::WalkParallel {
+    let mut wd = ignore::WalkBuilder::new(&root.path);
+    wd.hidden(root.ignore_hidden) // Whether to ignore hidden files
         .parents(false) // Don't read ignore files from parent directories
-        .follow_links(!config.ignore_symlinks) // Follow symbolic links
+        .follow_links(!root.ignore_symlinks) // Follow symbolic links
         .ignore(true) // Don't read .ignore files
-        .git_global(config.gitignore) // Don't read global gitignore file
-        .git_ignore(config.gitignore) // Don't read .gitignore files
-        .git_exclude(false); // Don't read .git/info/exclude files
+        .git_global(root.gitignore) // Don't read global gitignore file
+        .git_ignore(root.gitignore) // Don't read .gitignore files
+        .git_exclude(false) // Don't read .git/info/exclude files
+        .max_depth(root.max_depth)
+        .same_file_system(root.same_file_system);
+    wd.threads(WALKER_THREADS);
+    wd.build_parallel()
+}
 
-    for path in &paths[1..] {
-        wd.add(path);
+/// Reads the entries of the existing data file `db_path` located below a
+/// directory matching one of `roots`, leaving out those below the `walked`
+/// directories, which are indexed anew.
+fn previous_entries(
+    db_path: &Path,
+    roots: &[glob::Pattern],
+    walked: &[PathBuf],
+) -> std::io::Result<Vec<String>> {
+    let mut entries = Vec::new();
+    if roots.is_empty() || !db_path.exists() {
+        return Ok(entries);
     }
-    wd.add_ignore(ignores_fn(&database));
-    wd.threads(4);
-    wd.build_parallel()
+    let input_file = fs::File::open(db_path)?;
+    let decoder = lz4::Decoder::new(input_file)?;
+    let reader = io::BufReader::new(decoder);
+    let options = glob::MatchOptions {
+        require_literal_separator: true,
+        ..Default::default()
+    };
+    reader.for_byte_line(|_line| {
+        if let Ok(line) = str::from_utf8(_line) {
+            let path = Path::new(entry::Entry::parse(line).path);
+            if !walked.iter().any(|dir| path.starts_with(dir))
+                && path
+                    .ancestors()
+                    .any(|dir| roots.iter().any(|root| root.matches_path_with(dir, options)))
+            {
+                entries.push(line.to_string());
+            }
+        }
+        Ok(true)
+    })?;
+    Ok(entries)
+}
+
+/// Turns `path`, located below `mount_point`, into the path relative to the
+/// root of the volume which is stored in the data file, e.g.
+/// `/media/usb/music` into `/music`.
+fn volume_relative_path(path: &str, mount_point: &str) -> Option<String> {
+    if mount_point == "/" {
+        return Some(path.to_string());
+    }
+    match path.strip_prefix(mount_point) {
+        Some("") => Some("/".to_string()),
+        Some(rest) if rest.starts_with('/') => Some(rest.to_string()),
+        _ => None,
+    }
+}
+
+/// Turns a path stored relative to the root of a volume back into a path
+/// below `mount_point`.
+fn volume_absolute_path(path: &str, mount_point: &str) -> String {
+    match (mount_point, path) {
+        ("/", _) => path.to_string(),
+        (_, "/") => mount_point.to_string(),
+        _ => format!("{}{}", mount_point, path),
+    }
+}
+
+/// Returns where the entries of a database bound to a volume are to be
+/// found: `Ok(mount_point)` if the volume is mounted, `Err(volume_name)`
+/// otherwise. Returns `None` if the database isn't bound to a volume.
+fn db_volume_location(config: Option<&config::Config>) -> Option<Result<String, String>> {
+    let volume = config?.volume.as_ref()?;
+    Some(match mounts::volume_mount_point(volume) {
+        Some(mount_point) => Ok(mount_point.to_string_lossy().into_owned()),
+        None => Err(volume.to_string()),
+    })
 }
 
 fn update_databases(databases: Vec<String>) -> std::io::Result<()> {
@@ -314,195 +935,682 @@ fn update_databases(databases: Vec<String>) -> std::io::Result<()> {
 }
 
 fn update_database(db_name: &str) -> std::io::Result<()> {
-    let config_fn = config_fn(&db_name);
+    let config_fn = config_fn(db_name);
     if !config_fn.exists() {
-        eprintln!("Config file not found for database {}.\nPerhaps you forgot to run lolcate --create {} ?", &db_name, &db_name);
+        eprintln!("Config file not found for database {}.\nPerhaps you forgot to run lolcate create --db {} ?", &db_name, &db_name);
         process::exit(1);
     }
     let config = get_db_config(&config_fn);
-    check_db_config(&config, &config_fn);
-    let skip = config.skip;
-    let ignore_symlinks = config.ignore_symlinks;
-    let db_path = db_fn(&db_name);
+    if let Some(config::Volume { uuid: None, label: None }) = &config.volume {
+        eprintln!(
+            "Please edit file {:?} and specify the uuid or the label of the volume.",
+            config_fn
+        );
+        process::exit(1);
+    }
+    let mount_point = match &config.volume {
+        Some(volume) => match mounts::volume_mount_point(volume) {
+            Some(mount_point) => Some(mount_point),
+            None => {
+                eprintln!(
+                    "Volume {} of database {} isn't mounted. Keeping its current index.",
+                    volume, db_name
+                );
+                return Ok(());
+            }
+        },
+        None => None,
+    };
+    let (roots, kept_roots) = check_db_config(&config, &config_fn, mount_point.as_deref());
+    let mount_point = mount_point.map(|m| m.to_string_lossy().into_owned());
+    let kept_roots: Vec<glob::Pattern> = kept_roots
+        .iter()
+        .filter_map(|root| {
+            let path = root.path.to_string_lossy();
+            let path = match &mount_point {
+                Some(mount_point) => volume_relative_path(&path, mount_point)?,
+                None => path.into_owned(),
+            };
+            match root.unexpanded {
+                true => glob::Pattern::new(&path).ok(),
+                false => glob::Pattern::new(&glob::Pattern::escape(&path)).ok(),
+            }
+        })
+        .collect();
+    let walked_roots: Vec<PathBuf> = roots
+        .iter()
+        .filter_map(|root| match &mount_point {
+            Some(mount_point) => volume_relative_path(&root.path.to_string_lossy(), mount_point)
+                .map(PathBuf::from),
+            None => Some(root.path.clone()),
+        })
+        .collect();
+    let include_re = Arc::new(path_regex_set(&config.include_regex, &config_fn));
+    let exclude_re = Arc::new(path_regex_set(&config.exclude_regex, &config_fn));
+    let mounts = match config.prune_fs_types.is_empty() {
+        true => Vec::new(),
+        false => mounts::mounts(),
+    };
+    let db_path = db_fn(db_name);
     let parent_path = db_path.parent().unwrap();
     if !parent_path.exists() {
         fs::create_dir_all(parent_path)?;
     }
-    let output_fn = fs::File::create(db_path)?;
-    let (tx, rx) = channel::bounded::<ignore::DirEntry>(8000);
+    let kept_entries = previous_entries(&db_path, &kept_roots, &walked_roots)?;
+    // Written aside then renamed, so that queries and the daemon never read
+    // a partial data file
+    let output_file = tempfile::NamedTempFile::new_in(parent_path)?;
+    if scope() == Scope::System {
+        visibility::restrict_data_file(output_file.as_file())?;
+    }
+    let (tx, rx) = channel::bounded::<(ignore::DirEntry, entry::Metadata)>(8000);
+    let detect_mime = config.detect_mime;
+    let store_metadata = config.store_metadata;
+    let sort_entries = config.sort_entries;
 
     println!("Updating {}...", db_name);
+    let started = Instant::now();
+    let mut record = update_log::UpdateRecord {
+        started: chrono::Local::now().timestamp(),
+        threads: WALKER_THREADS,
+        ..Default::default()
+    };
+    let access_errors = Arc::new(AtomicU64::new(0));
 
-    let stdout_thread = thread::spawn(move || {
+    let stdout_thread = thread::spawn(move || -> std::io::Result<update_log::UpdateRecord> {
         let mut encoder = EncoderBuilder::new()
             .level(3)
             .block_mode(lz4::BlockMode::Linked)
             .block_size(lz4::BlockSize::Max256KB)
-            .build(output_fn)?;
-        for entry in rx {
-            match entry.path().to_str() {
-                Some(s) => {
-                    writeln!(encoder, "{}", s).unwrap();
+            .build(output_file)?;
+        // Entries are either written as they come, or sorted first
+        let mut sorted_entries = Vec::new();
+        for line in kept_entries {
+            record.kinds.count(entry::Entry::parse(&line).kind());
+            match sort_entries {
+                true => sorted_entries.push(line),
+                false => writeln!(encoder, "{}", line)?,
+            }
+        }
+        for (entry, metadata) in rx {
+            let kind = entry::Kind::from_entry(&entry);
+            let line = match entry.path().to_str() {
+                // Data files hold one path name per line
+                Some(s) if s.contains('\n') => {
+                    eprintln!("File name contains a newline: {:?}", entry.path());
+                    record.newlines += 1;
+                    None
                 }
-                _ => eprintln!("File name contains invalid unicode: {:?}", entry.path()),
+                Some(s) => match &mount_point {
+                    Some(mount_point) => volume_relative_path(s, mount_point)
+                        .map(|s| entry::format_line(&s, &metadata)),
+                    None => Some(entry::format_line(s, &metadata)),
+                },
+                _ => {
+                    eprintln!("File name contains invalid unicode: {:?}", entry.path());
+                    record.invalid_utf8 += 1;
+                    None
+                }
+            };
+            if line.is_some() {
+                record.kinds.count(kind);
+            }
+            match (line, sort_entries) {
+                (Some(line), true) => sorted_entries.push(line),
+                (Some(line), false) => writeln!(encoder, "{}", line)?,
+                (None, _) => {}
             }
         }
-        let (_output, result) = encoder.finish();
-        result
+        // Since fields are preceded by a NUL byte, this sorts by path name
+        sorted_entries.sort_unstable();
+        for line in sorted_entries {
+            writeln!(encoder, "{}", line)?;
+        }
+        let (output_file, result) = encoder.finish();
+        result?;
+        output_file.persist(db_path).map_err(|error| error.error)?;
+        record.entries = record.kinds.total();
+        Ok(record)
     });
 
-    walker(&config, &db_name).run(|| {
-        let tx = tx.clone();
-        Box::new(move |entry| { //: Result<ignore::DirEntry,ignore::Error>
-            use ignore::WalkState::*;
-            let entry = match entry {
-                    Ok(_entry) => _entry,
-                Err(err) => {
-                    eprintln!("failed to access entry ({})", err);
-                    return Continue
+    for root in roots {
+        if let Some(fs_type) = mounts::pruned_fs_type(&root.path, &mounts, &config.prune_fs_types) {
+            eprintln!(
+                "Skipping {}, which is on a {} filesystem.",
+                root.path.display(),
+                fs_type
+            );
+            continue;
+        }
+        let skip = root.skip;
+        let ignore_symlinks = root.ignore_symlinks;
+        let min_depth = root.min_depth.unwrap_or(0);
+        let pruned_mount_points = Arc::new(mounts::pruned_mount_points(
+            &root.path,
+            &mounts,
+            &config.prune_fs_types,
+        ));
+        let ignore_matcher = Arc::new(ignore_matcher(&root, &config, db_name));
+        walker(&root).run(|| {
+            let tx = tx.clone();
+            let ignore_matcher = ignore_matcher.clone();
+            let pruned_mount_points = pruned_mount_points.clone();
+            let include_re = include_re.clone();
+            let exclude_re = exclude_re.clone();
+            let access_errors = access_errors.clone();
+            Box::new(move |entry| { //: Result<ignore::DirEntry,ignore::Error>
+                use ignore::WalkState::*;
+                let entry = match entry {
+                        Ok(_entry) => _entry,
+                    Err(err) => {
+                        eprintln!("failed to access entry ({})", err);
+                        access_errors.fetch_add(1, Ordering::Relaxed);
+                        return Continue
+                    }
+                };
+                let is_dir = entry.file_type().is_some_and(|ft| ft.is_dir());
+                if is_ignored(&ignore_matcher, entry.path(), is_dir) {
+                    return Skip;
                 }
-            };
-            if skip != config::Skip::None || ignore_symlinks {
-                if let Some(ft) = entry.file_type() {
-                    if ft.is_dir() {
-                        if skip == config::Skip::Dirs {
+                if is_dir && pruned_mount_points.contains(entry.path()) {
+                    return Skip;
+                }
+                if entry.depth() < min_depth {
+                    return Continue;
+                }
+                if skip != config::Skip::None || ignore_symlinks {
+                    if let Some(ft) = entry.file_type() {
+                        if ft.is_dir() {
+                            if skip == config::Skip::Dirs {
+                                return Continue;
+                            };
+                        } else {
+                            if skip == config::Skip::Files {
+                                return Continue;
+                            };
+                        }
+                        if ignore_symlinks && ft.is_symlink() {
                             return Continue;
-                        };
+                        }
                     } else {
-                        if skip == config::Skip::Files {
+                        return Continue;
+                    }
+                }
+                if let Some(path) = entry.path().to_str() {
+                    if let Some(include_re) = include_re.as_ref() {
+                        if !include_re.is_match(path) {
                             return Continue;
-                        };
+                        }
                     }
-                    if ignore_symlinks && ft.is_symlink() {
-                        return Continue;
+                    if let Some(exclude_re) = exclude_re.as_ref() {
+                        if exclude_re.is_match(path) {
+                            return Continue;
+                        }
                     }
-                } else {
-                    return Continue;
                 }
-            }
-            match tx.send(entry) {
-                Ok(_) => ignore::WalkState::Continue,
-                Err(_) => ignore::WalkState::Quit,
-            }
-        })
-    });
+                let mut metadata = entry::Metadata::default();
+                if detect_mime && entry.file_type().is_some_and(|ft| ft.is_file()) {
+                    metadata.mime = entry::sniff_mime(entry.path());
+                }
+                if store_metadata {
+                    metadata.stat(&entry);
+                }
+                match tx.send((entry, metadata)) {
+                    Ok(_) => ignore::WalkState::Continue,
+                    Err(_) => ignore::WalkState::Quit,
+                }
+            })
+        });
+    }
     drop(tx);
-    stdout_thread.join().unwrap()
+    let mut record = stdout_thread.join().unwrap()?;
+    record.ended = chrono::Local::now().timestamp();
+    record.duration_ms = started.elapsed().as_millis() as u64;
+    record.access_errors = access_errors.load(Ordering::Relaxed);
+    println!(
+        "Indexed {} entries in {}.",
+        record.entries,
+        update_log::format_duration(record.duration_ms)
+    );
+    update_log::append(db_name, &record)
 }
 
-fn build_regex(pattern: &str, ignore_case: bool) -> Regex {
+/// Compiles the regex of a pattern, searched using smart case unless
+/// `ignore_case` is set.
+fn compile_regex(pattern: &str, ignore_case: Option<bool>) -> Result<Regex, regex::Error> {
     lazy_static! {
         static ref UPPER_RE: Regex = Regex::new(r"[[:upper:]]").unwrap();
     };
-    let re: Regex = match RegexBuilder::new(pattern)
-        .case_insensitive(ignore_case || !UPPER_RE.is_match(&pattern))
+    RegexBuilder::new(pattern)
+        .case_insensitive(ignore_case.unwrap_or_else(|| !UPPER_RE.is_match(pattern)))
         .build()
-    {
+}
+
+fn build_regex(pattern: &str, ignore_case: Option<bool>) -> Regex {
+    match compile_regex(pattern, ignore_case) {
         Ok(re) => re,
         Err(error) => {
             eprintln!("Invalid regex: {}", error);
             process::exit(1);
         }
-    };
-    re
+    }
 }
 
-fn lookup_databases(
+/// Builds the file types among `type_names` which are defined for a database.
+fn db_file_types(
+    type_names: &[&str],
+    types_map: &HashMap<String, config::TypeDef>,
+) -> Result<Vec<types::FileType>, String> {
+    type_names
+        .iter()
+        .filter(|name| types_map.contains_key(**name))
+        .map(|name| types::build(name, types_map))
+        .collect()
+}
+
+/// Compiles the comma-separated MIME type patterns of `--mime`.
+fn mime_filter(mimes: &str) -> Result<GlobSet, String> {
+    let mut builder = GlobSetBuilder::new();
+    for mime in mimes.split(',').filter(|m| !m.is_empty()) {
+        match Glob::new(mime) {
+            Ok(glob) => {
+                builder.add(glob);
+            }
+            Err(error) => return Err(format!("Invalid MIME type pattern: {}", error)),
+        }
+    }
+    builder.build().map_err(|error| format!("Invalid MIME type pattern: {}", error))
+}
+
+/// A database to be searched by a query.
+struct SearchedDatabase {
+    name: String,
+    config: Option<config::Config>,
+    data_file: PathBuf,
+    /// The data file, opened beforehand when reading it requires privileges
+    data: Option<fs::File>,
+    /// The requested file types defined for this database
+    types: Vec<types::FileType>,
+}
+
+/// Checks the databases `db_names` before searching them for the file types
+/// `type_names`. Databases defining none of these types are left out.
+fn searched_databases(
     db_names: Vec<String>,
+    type_names: &[&str],
+    global_types: &HashMap<String, config::TypeDef>,
+) -> Result<Vec<SearchedDatabase>, String> {
+    let configs = db_names
+        .iter()
+        .map(|db_name| match config_fn(db_name) {
+            _fn if _fn.exists() => read_db_config(&_fn).map(Some),
+            _ => Ok(None),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let types_maps: Vec<_> = configs
+        .iter()
+        .map(|config| db_types_map(global_types, config.as_ref()))
+        .collect();
+    // A type must be known to at least one of the databases
+    for name in type_names {
+        if !types_maps.iter().any(|types| types.contains_key(*name)) {
+            types::build(name, global_types)?;
+        }
+    }
+    let mut databases = Vec::new();
+    for ((name, config), types_map) in db_names.into_iter().zip(configs).zip(&types_maps) {
+        let types = db_file_types(type_names, types_map)?;
+        if !type_names.is_empty() && types.is_empty() {
+            // None of the requested types is defined for this database
+            continue;
+        }
+        databases.push(SearchedDatabase {
+            data_file: database_file(&name)?,
+            data: None,
+            name,
+            config,
+            types,
+        });
+    }
+    Ok(databases)
+}
+
+fn lookup_databases(
+    databases: &[SearchedDatabase],
     patterns_re: &[Regex],
-    types_re: &[Regex],
+    mime_filter: Option<&GlobSet>,
+    mut daemon: Option<daemon::Client>,
+    output: &mut output::Output,
 ) -> std::io::Result<()> {
-    for db_name in db_names {
-        lookup_database(&db_name, patterns_re, &types_re)?;
+    for database in databases {
+        if output.is_full() {
+            break;
+        }
+        let roots = match &database.config {
+            Some(config) if output.uses_roots() => {
+                match db_volume_location(Some(config)) {
+                    Some(Ok(mount_point)) => config.root_paths(Some(Path::new(&mount_point))),
+                    Some(Err(_)) => Vec::new(),
+                    None => config.root_paths(None),
+                }
+            }
+            _ => Vec::new(),
+        };
+        output.start_database(&database.name, roots);
+        match &mut daemon {
+            Some(daemon) => daemon.lookup(&database.name, output)?,
+            None => lookup_database(database, patterns_re, mime_filter, output)?,
+        }
     }
     Ok(())
 }
 
-fn lookup_database(
-    db_name: &str,
-    patterns_re: &[Regex],
-    types_re: &[Regex],
-) -> std::io::Result<()> {
-    let db_file = db_fn(&db_name);
+/// Returns the data file of the database `db_name`, checking that the
+/// database has been created and updated.
+fn database_file(db_name: &str) -> Result<PathBuf, String> {
+    let db_file = db_fn(db_name);
     if !db_file.parent().unwrap().exists() {
-        eprintln!(
-            "Database {} doesn't exist. Perhaps you forgot to run lolcate --create {} ?",
+        return Err(format!(
+            "Database {} doesn't exist. Perhaps you forgot to run lolcate create --db {} ?",
             &db_name, &db_name
-        );
-        process::exit(1);
+        ));
     }
     if !db_file.exists() {
-        eprintln!(
-            "Database {} is empty. Perhaps you forgot to run lolcate --update {} ?",
+        return Err(format!(
+            "Database {} is empty. Perhaps you forgot to run lolcate update --db {} ?",
             &db_name, &db_name
-        );
-        process::exit(1);
+        ));
     }
-    let input_file = fs::File::open(db_file)?;
-    let decoder = lz4::Decoder::new(input_file)?;
-    let reader = io::BufReader::new(decoder);
-    let stdout = io::stdout();
-    let lock = stdout.lock();
-    let mut w = io::BufWriter::new(lock); // DEFAULT_BUF_SIZE: usize = 8 * 1024;
+    Ok(db_file)
+}
+
+/// The warning given when querying a database whose volume isn't mounted.
+fn unmounted_volume_warning(db_name: &str, volume: &str) -> String {
+    format!(
+        "Volume {} of database {} isn't mounted. Its entries are shown as {}:PATH.",
+        volume, db_name, volume
+    )
+}
+
+/// Reads the lines of a data file from `reader`, and calls `found` with the
+/// path name and the entry of each one matching the query, until it returns
+/// false.
+fn search_entries<R: io::BufRead>(
+    reader: R,
+    volume_location: Option<&Result<String, String>>,
+    patterns_re: &[Regex],
+    types: &[types::FileType],
+    mime_filter: Option<&GlobSet>,
+    mut found: impl FnMut(&str, &entry::Entry) -> bool,
+) -> std::io::Result<()> {
+    let mut located_line = String::new();
     reader.for_byte_line(|_line| {
-        let line = str::from_utf8(_line).unwrap();
-        if types_re.len() > 0 {
-            if !types_re.iter().any(|re| re.is_match(&line)) {
+        let entry = entry::Entry::parse(str::from_utf8(_line).unwrap());
+        let mut line = entry.path;
+        if let Some(location) = volume_location {
+            located_line = match location {
+                Ok(mount_point) => volume_absolute_path(line, mount_point),
+                Err(volume) => format!("{}:{}", volume, line),
+            };
+            line = &located_line;
+        }
+        if !types.is_empty()
+            && !types.iter().any(|t| t.is_match(line, entry.mime())) {
+                return Ok(true);
+            }
+        if let Some(mime_filter) = mime_filter {
+            if !entry.mime().is_some_and(|mime| mime_filter.is_match(mime)) {
                 return Ok(true);
             }
         }
-        if !patterns_re.iter().all(|re| re.is_match(&line)) {
+        if !patterns_re.iter().all(|re| re.is_match(line)) {
             return Ok(true);
         }
-        #[allow(unused_must_use)]
-        {
-            w.write_all(&line.as_bytes());
-            w.write_all(b"\n");
-        }
-        Ok(true)
+        Ok(found(line, &entry))
     })
 }
 
-fn main() -> std::io::Result<()> {
-    let app = cli::build_cli();
-    let args = app.get_matches();
+fn lookup_database(
+    database: &SearchedDatabase,
+    patterns_re: &[Regex],
+    mime_filter: Option<&GlobSet>,
+    output: &mut output::Output,
+) -> std::io::Result<()> {
+    let volume_location = db_volume_location(database.config.as_ref());
+    if let Some(Err(volume)) = &volume_location {
+        eprintln!("{}", unmounted_volume_warning(&database.name, volume));
+    }
+    let input_file = match &database.data {
+        Some(data) => data.try_clone()?,
+        None => fs::File::open(&database.data_file)?,
+    };
+    let decoder = lz4::Decoder::new(input_file)?;
+    let reader = io::BufReader::new(decoder);
+    // Path names of system-wide databases are only shown to those who can see them
+    let mut visible = match scope() {
+        Scope::System => Some(visibility::Visibility::default()),
+        Scope::User => None,
+    };
+    search_entries(
+        reader,
+        volume_location.as_ref(),
+        patterns_re,
+        &database.types,
+        mime_filter,
+        |line, entry| {
+            if let Some(visible) = &mut visible {
+                if !visible.is_visible(line) {
+                    return true;
+                }
+            }
+            output.write(line, entry)
+        },
+    )
+}
 
-    create_global_config_if_needed()?;
+/// Adds the database `name` to `databases`, or the databases of the group
+/// `name`. `expanding` holds the groups being expanded, to detect cycles.
+fn expand_group(
+    name: &str,
+    groups: &HashMap<String, Vec<String>>,
+    expanding: &mut Vec<String>,
+    databases: &mut Vec<String>,
+) -> Result<(), String> {
+    match groups.get(name) {
+        Some(members) => {
+            if db_config_dir(name).exists() {
+                return Err(format!(
+                    "{} is the name of both a database and a group. Please rename one of them.",
+                    name
+                ));
+            }
+            if expanding.iter().any(|group| group == name) {
+                return Err(format!("Group {} includes itself.", name));
+            }
+            expanding.push(name.to_string());
+            for member in members {
+                expand_group(member, groups, expanding, databases)?;
+            }
+            expanding.pop();
+        }
+        None => {
+            validate_db_name(name)?;
+            if !databases.iter().any(|db| db == name) {
+                databases.push(name.to_string());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the databases designated by a comma-separated list of database
+/// and group names, in order and without duplicates.
+fn resolve_databases(
+    names: &str,
+    groups: &HashMap<String, Vec<String>>,
+) -> Result<Vec<String>, String> {
+    let mut databases = Vec::new();
+    for name in names.split(',').filter(|n| !n.is_empty()) {
+        expand_group(name, groups, &mut Vec::new(), &mut databases)?;
+    }
+    if databases.is_empty() {
+        return Err("No database given.".to_string());
+    }
+    Ok(databases)
+}
+
+/// Returns the database given by $LOLCATE_DB or by the defaults of the
+/// global configuration, or "default".
+fn default_database_name(defaults: &config::Defaults) -> String {
+    env::var("LOLCATE_DB")
+        .ok()
+        .filter(|db_name| !db_name.is_empty())
+        .or_else(|| defaults.database.clone())
+        .unwrap_or_else(|| "default".to_string())
+}
 
-    let database = args.value_of("database").unwrap();
-    let databases: Vec<String> = match args.is_present("all") {
-        true => database_names(lolcate_config_path()),
-        false => vec![database.to_string()],
+/// Returns the database given by --db, or the default one.
+fn database_name(args: &clap::ArgMatches, defaults: &config::Defaults) -> String {
+    match args.value_of("database") {
+        Some(db_name) => db_name.to_string(),
+        None => default_database_name(defaults),
+    }
+}
+
+/// Returns the databases designated by --all, i.e. all of them except those
+/// setting hide_from_all.
+fn all_databases() -> Result<Vec<String>, String> {
+    let mut databases = Vec::new();
+    for db_name in database_names(lolcate_config_path()) {
+        let hidden = match config_fn(&db_name) {
+            _fn if _fn.exists() => read_db_config(&_fn)?.hide_from_all,
+            _ => false,
+        };
+        if !hidden {
+            databases.push(db_name);
+        }
+    }
+    Ok(databases)
+}
+
+fn selected_databases(
+    args: &clap::ArgMatches,
+    global_config: &config::GlobalConfig,
+) -> Vec<String> {
+    let databases = match args.is_present("all") {
+        true => all_databases(),
+        false => resolve_databases(
+            &database_name(args, &global_config.defaults),
+            &global_config.groups,
+        ),
     };
+    match databases {
+        Ok(databases) => databases,
+        Err(error) => {
+            eprintln!("{}", error);
+            process::exit(1);
+        }
+    }
+}
+
+/// Returns the databases given by --db, or all of them.
+fn stats_databases(args: &clap::ArgMatches, global_config: &config::GlobalConfig) -> Vec<String> {
+    match args.is_present("database") || args.is_present("all") {
+        true => selected_databases(args, global_config),
+        false => {
+            let mut db_names = database_names(lolcate_config_path());
+            db_names.sort();
+            db_names
+        }
+    }
+}
 
-    if args.is_present("create") {
-        create_database(&database)?;
-        process::exit(0);
+fn color_choice(args: &clap::ArgMatches, defaults: &config::Defaults) -> ColorChoice {
+    let color = match args.value_of("color") {
+        Some("always") => config::ColorWhen::Always,
+        Some("never") => config::ColorWhen::Never,
+        Some(_) => config::ColorWhen::Auto,
+        None => defaults.color.unwrap_or(config::ColorWhen::Auto),
+    };
+    match color {
+        config::ColorWhen::Always => ColorChoice::Always,
+        config::ColorWhen::Never => ColorChoice::Never,
+        config::ColorWhen::Auto if io::stdout().is_terminal() => ColorChoice::Auto,
+        config::ColorWhen::Auto => ColorChoice::Never,
     }
+}
 
-    if args.is_present("update") {
-        update_databases(databases)?;
-        process::exit(0);
+fn output_format(args: &clap::ArgMatches, defaults: &config::Defaults) -> output::Format {
+    let format = if args.is_present("json") {
+        Some("json")
+    } else if args.is_present("jsonl") {
+        Some("jsonl")
+    } else {
+        args.value_of("format").or(defaults.format.as_deref())
+    };
+    match format {
+        None => output::Format::Plain,
+        Some(format) => match output::Format::parse(format) {
+            Ok(format) => format,
+            Err(error) => {
+                eprintln!("{}", error);
+                process::exit(1);
+            }
+        },
     }
+}
+
+fn query_databases(
+    args: &clap::ArgMatches,
+    global_config: &config::GlobalConfig,
+) -> std::io::Result<()> {
+    let defaults = &global_config.defaults;
 
-    if args.is_present("info") {
-        info_databases()?;
-        process::exit(0);
+    if let Some(paths) = args.values_of("touch") {
+        visibility::drop_privileges();
+        let paths = paths
+            .map(|path| match std::path::absolute(path) {
+                Ok(path) => path.to_string_lossy().into_owned(),
+                Err(error) => {
+                    eprintln!("Invalid path {}: {}", path, error);
+                    process::exit(1);
+                }
+            })
+            .collect::<Vec<_>>();
+        return history::record(&paths);
     }
 
-    // lookup
-    let types_map = get_types_map();
-    let types_re = args
+    let type_names = args
         .value_of("type")
         .unwrap_or_default()
-        .split(",")
-        .map(|n| types_map.get(n))
-        .filter(|t| t.is_some())
-        .map(|t| t.unwrap())
-        .map(|t| Regex::new(&t).unwrap())
+        .split(',')
+        .filter(|n| !n.is_empty())
         .collect::<Vec<_>>();
 
-    let ignore_case = args.is_present("ignore_case");
+    let mime_filter = args.value_of("mime").map(|mimes| match mime_filter(mimes) {
+        Ok(mime_filter) => mime_filter,
+        Err(error) => {
+            eprintln!("{}", error);
+            process::exit(1);
+        }
+    });
+
+    let ignore_case = match (args.is_present("ignore_case"), args.is_present("case_sensitive")) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => defaults.ignore_case,
+    };
+
+    let limit = match args.value_of("limit") {
+        Some(limit) => match limit.parse::<usize>() {
+            Ok(limit) => Some(limit),
+            Err(_) => {
+                eprintln!("Invalid limit: {}", limit);
+                process::exit(1);
+            }
+        },
+        None => defaults.limit,
+    };
 
     let patterns_re = args
         .values_of("pattern")
@@ -514,10 +1622,277 @@ fn main() -> std::io::Result<()> {
         .unwrap_or_default()
         .map(|p| build_regex(&format!("/[^/]*{}[^/]*$", p), ignore_case));
 
-    lookup_databases(
-        databases,
-        &patterns_re.chain(bn_patterns_re).collect::<Vec<_>>(),
-        &types_re,
-    )?;
-    Ok(())
+    let format = output_format(args, defaults);
+    if args.is_present("summary") && !format.is_json() {
+        eprintln!("A summary can only be written along with JSON output.");
+        process::exit(1);
+    }
+    let mut output = output::Output::new(format, limit, args.is_present("summary"));
+    if let Some(key) = args.value_of("sort") {
+        match sort::SortKey::parse(key) {
+            Some(key) => output.sort(key, args.is_present("reverse")),
+            None => {
+                eprintln!("Unknown sort key: {}", key);
+                process::exit(1);
+            }
+        }
+    }
+    if args.is_present("rank") {
+        // Only frecency is supported for now
+        match history::History::load() {
+            Ok(history) => output.rank(history.scores()),
+            Err(error) => {
+                eprintln!("Cannot read the history: {}", error);
+                process::exit(1);
+            }
+        }
+    }
+    if args.is_present("unique") {
+        output.unique();
+    }
+    let record_history = defaults.history.unwrap_or(false);
+    if record_history {
+        output.record_visits();
+    }
+    let interactive = args.is_present("interactive");
+    if interactive {
+        output.collect();
+    }
+    if let Some(command) = args.values_of("exec") {
+        let threads = match args.value_of("threads") {
+            Some(threads) => match threads.parse::<usize>() {
+                Ok(threads) if threads > 0 => threads,
+                _ => {
+                    eprintln!("Invalid number of threads: {}", threads);
+                    process::exit(1);
+                }
+            },
+            None => thread::available_parallelism().map_or(1, |n| n.get()),
+        };
+        output.execute(exec::Executor::new(exec::CommandTemplate::new(command), threads));
+    }
+    if let Some(command) = args.values_of("exec_batch") {
+        output.execute(exec::Executor::batch(exec::CommandTemplate::new(command)));
+    }
+
+    let patterns_re = patterns_re.chain(bn_patterns_re).collect::<Vec<_>>();
+    // The daemon doesn't know who can see the path names of system-wide databases
+    let daemon = match scope() {
+        Scope::User if !args.is_present("no_daemon") => daemon::Client::connect(daemon::Query {
+            patterns: patterns_re.iter().map(|re| re.as_str().to_string()).collect(),
+            ignore_case,
+            types: type_names.iter().map(|name| name.to_string()).collect(),
+            mime: args.value_of("mime").map(str::to_string),
+        }),
+        _ => None,
+    };
+    let mut databases = match searched_databases(
+        selected_databases(args, global_config),
+        &type_names,
+        &global_config.types,
+    ) {
+        Ok(databases) => databases,
+        Err(error) => {
+            eprintln!("{}", error);
+            process::exit(1);
+        }
+    };
+    if scope() == Scope::System {
+        for database in &mut databases {
+            match fs::File::open(&database.data_file) {
+                Ok(data) => database.data = Some(data),
+                Err(error) => {
+                    eprintln!(
+                        "Cannot read {}: {}\nQuerying the system-wide databases requires lolcate to be installed setgid {}.",
+                        database.data_file.display(),
+                        error,
+                        visibility::DATA_GROUP
+                    );
+                    process::exit(1);
+                }
+            }
+        }
+        visibility::drop_privileges();
+    }
+    if let Err(error) =
+        lookup_databases(&databases, &patterns_re, mime_filter.as_ref(), daemon, &mut output)
+    {
+        // Still write the matches found so far, and record the history
+        if let Err(error) = output.finish() {
+            if error.kind() != io::ErrorKind::BrokenPipe {
+                eprintln!("{}", error);
+            }
+        }
+        eprintln!("{}", error);
+        process::exit(1);
+    }
+    if interactive {
+        let candidates = output.take_candidates()?;
+        let db_names = output.database_names();
+        let picker = picker::Picker::new(&candidates, &db_names, |pattern| {
+            compile_regex(pattern, ignore_case)
+        });
+        let outcome = picker.run()?;
+        let opened: Vec<&str> = outcome.opened.iter().map(|&c| candidates[c].path()).collect();
+        match outcome.chosen {
+            Some(chosen) => {
+                for path in opened {
+                    output.visit(path);
+                }
+                for candidate in chosen {
+                    output.write_candidate(&candidates[candidate]);
+                }
+            }
+            None => {
+                if record_history {
+                    if let Err(error) = history::record(&opened) {
+                        eprintln!("Cannot record the history: {}", error);
+                    }
+                }
+                // Like fzf
+                process::exit(130)
+            }
+        }
+    }
+    match output.finish() {
+        Ok(true) => Ok(()),
+        Ok(false) => process::exit(1),
+        // The reader is gone, e.g. `lolcate foo | head`
+        Err(error) if error.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        Err(error) => Err(error),
+    }
+}
+
+fn main() -> std::io::Result<()> {
+    let app = cli::build_cli();
+    let args = app.get_matches();
+
+    let system = args.is_present("system")
+        || args
+            .subcommand()
+            .1
+            .is_some_and(|sub_args| sub_args.is_present("system"));
+    let is_query = match args.subcommand() {
+        ("query", _) => true,
+        ("", _) => !["create", "update", "info", "stats"]
+            .iter()
+            .any(|mode| args.is_present(mode)),
+        _ => false,
+    };
+    // Only queries of the system-wide databases read their data files
+    if !(system && is_query) {
+        visibility::drop_privileges();
+    }
+    if system {
+        SCOPE.set(Scope::System).unwrap();
+    } else {
+        create_global_config_if_needed()?;
+    }
+    let global_config = get_global_config(&global_config_fn());
+    let defaults = &global_config.defaults;
+
+    match args.subcommand() {
+        ("create", Some(sub_args)) => create_database(
+            &database_name(sub_args, defaults),
+            sub_args.is_present("single_file"),
+        ),
+        ("update", Some(sub_args)) => {
+            update_databases(selected_databases(sub_args, &global_config))
+        }
+        ("info", Some(sub_args)) => info_databases(color_choice(sub_args, defaults)),
+        ("stats", Some(sub_args)) => stats::show_stats(
+            stats_databases(sub_args, &global_config),
+            color_choice(sub_args, defaults),
+        ),
+        ("query", Some(sub_args)) => query_databases(sub_args, &global_config),
+        ("daemon", Some(_)) => daemon::serve(),
+        ("serve", Some(sub_args)) => serve::serve(
+            sub_args.value_of("listen").unwrap(),
+            sub_args.is_present("allow_remote"),
+        ),
+        ("edit", Some(sub_args)) => edit_config(sub_args, defaults),
+        ("remove", Some(sub_args)) => remove_database(
+            sub_args.value_of("name").unwrap(),
+            sub_args.is_present("yes"),
+        ),
+        ("rename", Some(sub_args)) => rename_database(
+            sub_args.value_of("old_name").unwrap(),
+            sub_args.value_of("new_name").unwrap(),
+        ),
+        _ => {
+            // Mode flags from versions prior to subcommands
+            if args.is_present("create") {
+                create_database(&database_name(&args, defaults), false)
+            } else if args.is_present("update") {
+                update_databases(selected_databases(&args, &global_config))
+            } else if args.is_present("info") {
+                info_databases(color_choice(&args, defaults))
+            } else if args.is_present("stats") {
+                stats::show_stats(
+                    stats_databases(&args, &global_config),
+                    color_choice(&args, defaults),
+                )
+            } else {
+                query_databases(&args, &global_config)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn anchor_rule_prefixes_rules_with_slashes() {
+        let root = Path::new("/home/me/src");
+        assert_eq!(anchor_rule(root, "/build"), "/home/me/src/build");
+        assert_eq!(anchor_rule(root, "a/b/"), "/home/me/src/a/b/");
+        assert_eq!(anchor_rule(root, "!/build"), "!/home/me/src/build");
+        assert_eq!(anchor_rule(root, "**/target"), "/home/me/src/**/target");
+    }
+
+    #[test]
+    fn anchor_rule_keeps_file_name_rules() {
+        let root = Path::new("/home/me/src");
+        assert_eq!(anchor_rule(root, "*.o"), "*.o");
+        assert_eq!(anchor_rule(root, "target/"), "target/");
+        assert_eq!(anchor_rule(root, "!keep.o"), "!keep.o");
+        assert_eq!(anchor_rule(root, "# a/comment"), "# a/comment");
+    }
+
+    fn matcher(rules: &[&str]) -> Gitignore {
+        let mut builder = GitignoreBuilder::new("/home/me/.config/lolcate/db");
+        for rule in rules {
+            builder.add_line(None, rule).unwrap();
+        }
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn is_ignored_matches_anchored_rules_anywhere() {
+        let root = Path::new("/tmp");
+        let matcher = matcher(&[&anchor_rule(root, "/build"), &anchor_rule(root, "a/*.log")]);
+        assert!(is_ignored(&matcher, Path::new("/tmp/build"), true));
+        assert!(is_ignored(&matcher, Path::new("/tmp/a/x.log"), false));
+        assert!(!is_ignored(&matcher, Path::new("/tmp/sub/build"), true));
+        assert!(!is_ignored(&matcher, Path::new("/tmp/a/b/x.log"), false));
+        assert!(!is_ignored(&matcher, Path::new("/var/tmp/build"), true));
+    }
+
+    #[test]
+    fn is_ignored_applies_later_rules_first() {
+        let matcher = matcher(&["*.log", "target/", "!keep.log", "/home/me/.config/lolcate/db/x"]);
+        assert!(is_ignored(&matcher, Path::new("/data/a.log"), false));
+        assert!(!is_ignored(&matcher, Path::new("/data/keep.log"), false));
+        assert!(is_ignored(&matcher, Path::new("/src/target"), true));
+        assert!(!is_ignored(&matcher, Path::new("/src/target"), false));
+        assert!(is_ignored(&matcher, Path::new("/home/me/.config/lolcate/db/x"), false));
+    }
+
+    #[test]
+    fn anchor_rule_escapes_the_root() {
+        let root = Path::new("/data/[old]*");
+        assert_eq!(anchor_rule(root, "/x"), "/data/\\[old\\]\\*/x");
+    }
 }
@@ -0,0 +1,222 @@
+/*
+ * This file is part of Lolcate.
+ *
+ * Copyright © 2019 Nicolas Girard
+ *
+ * Lolcate is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Lolcate is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Lolcate.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::config::Volume;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// A mounted filesystem, as listed in `/proc/self/mounts`.
+#[derive(Debug)]
+pub struct Mount {
+    pub device: PathBuf,
+    pub mount_point: PathBuf,
+    pub fs_type: String,
+}
+
+/// Decodes the octal escapes (e.g. `\040` for a space) used in the fields
+/// of `/proc/self/mounts`.
+#[cfg(target_os = "linux")]
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\'
+            && i + 3 < bytes.len()
+            && bytes[i + 1..i + 4]
+                .iter()
+                .all(|b| (b'0'..=b'7').contains(b))
+        {
+            out.push(
+                (bytes[i + 1] - b'0') * 64 + (bytes[i + 2] - b'0') * 8 + (bytes[i + 3] - b'0'),
+            );
+            i += 4;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(target_os = "linux")]
+pub fn mounts() -> Vec<Mount> {
+    let contents = match std::fs::read_to_string("/proc/self/mounts") {
+        Ok(contents) => contents,
+        Err(error) => {
+            eprintln!("Cannot read the list of mounted filesystems: {}", error);
+            return Vec::new();
+        }
+    };
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let device = fields.next()?;
+            let mount_point = fields.next()?;
+            let fs_type = fields.next()?;
+            Some(Mount {
+                device: PathBuf::from(unescape(device)),
+                mount_point: PathBuf::from(unescape(mount_point)),
+                fs_type: unescape(fs_type),
+            })
+        })
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn mounts() -> Vec<Mount> {
+    eprintln!("Listing mounted filesystems is only supported on Linux.");
+    Vec::new()
+}
+
+fn is_pruned(mount: &Mount, fs_types: &[String]) -> bool {
+    fs_types
+        .iter()
+        .any(|t| t.eq_ignore_ascii_case(&mount.fs_type))
+}
+
+/// Returns the mount points of the filesystems whose type is one of
+/// `fs_types`, located below `root`, as reached by walking `root`. Only
+/// `root` is resolved, so that mount points are told apart by their path
+/// names even if `root` is reached through a symbolic link, without
+/// accessing the possibly unresponsive pruned filesystems.
+pub fn pruned_mount_points(root: &Path, mounts: &[Mount], fs_types: &[String]) -> HashSet<PathBuf> {
+    let canonical_root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+    mounts
+        .iter()
+        .filter(|m| is_pruned(m, fs_types))
+        .filter_map(|m| m.mount_point.strip_prefix(&canonical_root).ok())
+        .map(|relative| root.join(relative))
+        .collect()
+}
+
+/// Returns the type of the filesystem containing `path` if it is one of `fs_types`.
+pub fn pruned_fs_type<'a>(
+    path: &Path,
+    mounts: &'a [Mount],
+    fs_types: &[String],
+) -> Option<&'a str> {
+    let path = path.canonicalize().ok()?;
+    mounts
+        .iter()
+        .filter(|m| path.starts_with(&m.mount_point))
+        .max_by_key(|m| m.mount_point.as_os_str().len())
+        .filter(|m| is_pruned(m, fs_types))
+        .map(|m| m.fs_type.as_str())
+}
+
+/// Encodes a filesystem label the way udev does in `/dev/disk/by-label`.
+fn encode_label(label: &str) -> String {
+    let mut out = String::with_capacity(label.len());
+    for c in label.chars() {
+        if c.is_ascii_alphanumeric() || "#+-.:=@_".contains(c) || !c.is_ascii() {
+            out.push(c);
+        } else {
+            out.push_str(&format!("\\x{:02x}", c as u32));
+        }
+    }
+    out
+}
+
+/// Returns the device holding the filesystem identified by `volume`.
+pub fn volume_device(volume: &Volume) -> Option<PathBuf> {
+    let link = match (&volume.uuid, &volume.label) {
+        (Some(uuid), _) => Path::new("/dev/disk/by-uuid").join(uuid),
+        (None, Some(label)) => Path::new("/dev/disk/by-label").join(encode_label(label)),
+        (None, None) => return None,
+    };
+    link.canonicalize().ok()
+}
+
+/// Returns the mount point of the filesystem identified by `volume`, if it
+/// is mounted.
+pub fn volume_mount_point(volume: &Volume) -> Option<PathBuf> {
+    let device = volume_device(volume)?;
+    mounts()
+        .into_iter()
+        .find(|m| {
+            m.device.starts_with("/dev") && m.device.canonicalize().is_ok_and(|d| d == device)
+        })
+        .map(|m| m.mount_point)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn unescape_decodes_octal_escapes() {
+        assert_eq!(unescape("/media/My\\040Disk"), "/media/My Disk");
+        assert_eq!(unescape("a\\011b\\012c\\134d"), "a\tb\nc\\d");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn unescape_keeps_incomplete_escapes() {
+        assert_eq!(unescape("/plain/path"), "/plain/path");
+        assert_eq!(unescape("x\\04"), "x\\04");
+        assert_eq!(unescape("x\\089"), "x\\089");
+        assert_eq!(unescape("x\\"), "x\\");
+    }
+
+    fn mount(mount_point: &str, fs_type: &str) -> Mount {
+        Mount {
+            device: PathBuf::from(fs_type),
+            mount_point: PathBuf::from(mount_point),
+            fs_type: fs_type.to_string(),
+        }
+    }
+
+    #[test]
+    fn pruned_mount_points_filters_by_type_and_root() {
+        let mounts = vec![
+            mount("/", "ext4"),
+            mount("/nonexistent/lolcate/nfs", "nfs"),
+            mount("/nonexistent/lolcate/a/proc", "proc"),
+            mount("/nonexistent/other/nfs", "nfs"),
+        ];
+        let root = Path::new("/nonexistent/lolcate");
+        assert!(pruned_mount_points(root, &mounts, &["tmpfs".to_string()]).is_empty());
+        let pruned = pruned_mount_points(root, &mounts, &["NFS".to_string(), "proc".to_string()]);
+        let mut pruned: Vec<_> = pruned.into_iter().collect();
+        pruned.sort();
+        assert_eq!(
+            pruned,
+            vec![
+                PathBuf::from("/nonexistent/lolcate/a/proc"),
+                PathBuf::from("/nonexistent/lolcate/nfs")
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pruned_mount_points_follows_a_linked_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().canonicalize().unwrap().join("target");
+        std::fs::create_dir(&target).unwrap();
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        let mounts = vec![mount(target.join("nfs").to_str().unwrap(), "nfs")];
+        let pruned = pruned_mount_points(&link, &mounts, &["nfs".to_string()]);
+        assert_eq!(pruned.into_iter().collect::<Vec<_>>(), vec![link.join("nfs")]);
+    }
+}
@@ -0,0 +1,443 @@
+/*
+ * This file is part of Lolcate.
+ *
+ * Copyright © 2019 Nicolas Girard
+ *
+ * Lolcate is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Lolcate is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Lolcate.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Output of the path names found by queries.
+
+use crate::entry::Entry;
+use crate::exec::Executor;
+use crate::history;
+use crate::sort::{SortKey, Sorter};
+use crate::template::{Context, Template};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Instant;
+
+/// How the path names found by a query are written.
+pub enum Format {
+    /// One path name per line
+    Plain,
+    /// Path names terminated by NUL characters
+    Null,
+    /// A JSON array of records
+    Json,
+    /// One JSON record per line
+    JsonLines,
+    /// One rendered template per line
+    Template(Template),
+}
+
+impl Format {
+    pub fn parse(format: &str) -> Result<Format, String> {
+        match format {
+            "plain" => Ok(Format::Plain),
+            "null" => Ok(Format::Null),
+            "json" => Ok(Format::Json),
+            "jsonl" => Ok(Format::JsonLines),
+            _ if Template::is_template(format) => Template::parse(format).map(Format::Template),
+            _ => Err(format!("Unknown output format: {}", format)),
+        }
+    }
+
+    pub fn is_json(&self) -> bool {
+        matches!(self, Format::Json | Format::JsonLines)
+    }
+}
+
+#[derive(Serialize)]
+struct DatabaseCount<'a> {
+    name: &'a str,
+    matches: usize,
+}
+
+/// A record of the JSON output.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Record<'a> {
+    Match {
+        database: &'a str,
+        path: &'a str,
+        basename: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        kind: Option<&'static str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        size: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        mtime: Option<i64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        mime: Option<&'a str>,
+    },
+    Summary {
+        matches: usize,
+        databases: Vec<DatabaseCount<'a>>,
+        elapsed_ms: u64,
+    },
+}
+
+/// A path name kept for the interactive picker.
+pub struct Candidate {
+    /// Index of the database it was found in
+    pub db: usize,
+    /// The path name followed by the metadata fields of its entry
+    pub line: String,
+}
+
+impl Candidate {
+    pub fn path(&self) -> &str {
+        Entry::parse(&self.line).path
+    }
+}
+
+/// A database being queried.
+struct Database {
+    name: String,
+    /// Indexed directories, if needed
+    roots: Vec<PathBuf>,
+    matches: usize,
+}
+
+/// Writes the path names found by a query, to the standard output unless
+/// another writer is given.
+pub struct Output {
+    writer: io::BufWriter<Box<dyn Write>>,
+    format: Format,
+    remaining: Option<usize>,
+    summary: bool,
+    started: Instant,
+    databases: Vec<Database>,
+    /// Index of the database the path names being written come from
+    current: usize,
+    sorter: Option<Sorter>,
+    /// Runs commands on the path names instead of writing them
+    executor: Option<Executor>,
+    /// Path names kept for the interactive picker instead of being written
+    candidates: Option<Vec<Candidate>>,
+    unique: bool,
+    /// Path names written so far, when --unique can't rely on sorting
+    seen: Option<HashSet<String>>,
+    /// Path names opened, i.e. chosen in the picker or given to commands,
+    /// to be recorded in the history
+    visited: Option<Vec<String>>,
+    records: usize,
+    line: String,
+    /// The error which stopped the output, e.g. a closed pipe
+    error: Option<io::Error>,
+}
+
+impl Output {
+    pub fn new(format: Format, limit: Option<usize>, summary: bool) -> Output {
+        Output::to_writer(Box::new(io::stdout().lock()), format, limit, summary)
+    }
+
+    pub fn to_writer(
+        writer: Box<dyn Write>,
+        format: Format,
+        limit: Option<usize>,
+        summary: bool,
+    ) -> Output {
+        Output {
+            writer: io::BufWriter::new(writer),
+            format,
+            remaining: limit,
+            summary,
+            started: Instant::now(),
+            databases: Vec::new(),
+            current: 0,
+            sorter: None,
+            executor: None,
+            candidates: None,
+            unique: false,
+            seen: None,
+            visited: None,
+            records: 0,
+            line: String::new(),
+            error: None,
+        }
+    }
+
+    /// Sorts the path names of all the databases before writing them.
+    pub fn sort(&mut self, key: SortKey, reverse: bool) {
+        self.sorter = Some(Sorter::new(key, reverse));
+    }
+
+    /// Ranks the path names by their frecency `scores` before writing them,
+    /// sorting the others by path name unless `sort` was called. This must
+    /// be called after `sort`.
+    pub fn rank(&mut self, scores: HashMap<String, f64>) {
+        self.sorter
+            .get_or_insert_with(|| Sorter::new(SortKey::Path, false))
+            .rank(scores);
+    }
+
+    /// Records the path names opened in the history when finishing.
+    pub fn record_visits(&mut self) {
+        self.visited = Some(Vec::new());
+    }
+
+    /// Adds a path name opened outside of the output, e.g. from the picker.
+    pub fn visit(&mut self, path: &str) {
+        if let Some(visited) = &mut self.visited {
+            visited.push(path.to_string());
+        }
+    }
+
+    /// Keeps the path names for the interactive picker instead of writing
+    /// them. They are returned by `take_candidates`.
+    pub fn collect(&mut self) {
+        self.candidates = Some(Vec::new());
+    }
+
+    pub fn database_names(&self) -> Vec<String> {
+        self.databases.iter().map(|db| db.name.clone()).collect()
+    }
+
+    /// Returns the path names kept for the interactive picker, after which
+    /// path names are written again, regardless of --limit.
+    pub fn take_candidates(&mut self) -> io::Result<Vec<Candidate>> {
+        self.drain_sorter()?;
+        self.remaining = None;
+        Ok(self.candidates.take().unwrap_or_default())
+    }
+
+    /// Writes a path name chosen using the interactive picker.
+    pub fn write_candidate(&mut self, candidate: &Candidate) {
+        self.current = candidate.db;
+        let entry = Entry::parse(&candidate.line);
+        if self.executor.is_none() {
+            self.visit(entry.path);
+        }
+        self.emit(entry.path, &entry);
+    }
+
+    /// Runs commands on the path names instead of writing them.
+    pub fn execute(&mut self, executor: Executor) {
+        self.executor = Some(executor);
+    }
+
+    /// Writes each path name only once, even if found in several databases.
+    /// This must be called after `sort`.
+    pub fn unique(&mut self) {
+        self.unique = true;
+        if !self.sorter.as_ref().is_some_and(|sorter| sorter.key().groups_paths()) {
+            self.seen = Some(HashSet::new());
+        }
+    }
+
+    /// Whether no more path names can be written, because of --limit or of
+    /// a write error.
+    pub fn is_full(&self) -> bool {
+        self.remaining == Some(0) || self.error.is_some()
+    }
+
+    /// Whether the indexed directories of the databases are needed.
+    pub fn uses_roots(&self) -> bool {
+        match &self.format {
+            Format::Template(template) => template.uses_root(),
+            _ => false,
+        }
+    }
+
+    /// Sets the database the next path names come from, along with its
+    /// indexed directories if they are needed.
+    pub fn start_database(&mut self, db_name: &str, roots: Vec<PathBuf>) {
+        self.current = self.databases.len();
+        self.databases.push(Database {
+            name: db_name.to_string(),
+            roots,
+            matches: 0,
+        });
+    }
+
+    /// Adds a path name, which might differ from the one of `entry` when
+    /// located on a volume, and returns whether more can be added.
+    pub fn write(&mut self, path: &str, entry: &Entry) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        if let Some(seen) = &mut self.seen {
+            if !seen.insert(path.to_string()) {
+                return true;
+            }
+        }
+        match &mut self.sorter {
+            Some(sorter) => {
+                if let Err(error) = sorter.push(self.current, path, entry.fields()) {
+                    eprintln!("Cannot sort the path names: {}", error);
+                    process::exit(1);
+                }
+                true
+            }
+            None => self.emit(path, entry),
+        }
+    }
+
+    fn emit(&mut self, path: &str, entry: &Entry) -> bool {
+        if let Some(candidates) = &mut self.candidates {
+            candidates.push(Candidate {
+                db: self.current,
+                line: format!("{}{}", path, entry.fields()),
+            });
+            if let Some(remaining) = &mut self.remaining {
+                *remaining -= 1;
+            }
+            return !self.is_full();
+        }
+        let database = &self.databases[self.current];
+        let result = match &self.format {
+            _ if self.executor.is_some() => {
+                if let Some(executor) = &mut self.executor {
+                    executor.push(path);
+                }
+                if let Some(visited) = &mut self.visited {
+                    visited.push(path.to_string());
+                }
+                Ok(())
+            }
+            Format::Plain | Format::Null => {
+                let terminator = match self.format {
+                    Format::Null => b'\0',
+                    _ => b'\n',
+                };
+                self.writer
+                    .write_all(path.as_bytes())
+                    .and_then(|_| self.writer.write_all(&[terminator]))
+            }
+            Format::Template(template) => {
+                // The innermost indexed directory containing the path name
+                let root = database
+                    .roots
+                    .iter()
+                    .filter(|root| Path::new(path).starts_with(root))
+                    .max_by_key(|root| root.as_os_str().len());
+                self.line.clear();
+                template.render(
+                    &mut self.line,
+                    &Context {
+                        database: &database.name,
+                        path,
+                        root: root.map(|root| root.as_path()),
+                        entry,
+                    },
+                );
+                self.line.push('\n');
+                self.writer.write_all(self.line.as_bytes())
+            }
+            Format::Json | Format::JsonLines => {
+                let database = database.name.clone();
+                let basename = Path::new(path)
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .unwrap_or(path);
+                self.write_record(&Record::Match {
+                    database: &database,
+                    path,
+                    basename,
+                    kind: entry.kind().map(|kind| kind.as_str()),
+                    size: entry.size(),
+                    mtime: entry.mtime(),
+                    mime: entry.mime(),
+                })
+            }
+        };
+        if let Err(error) = result {
+            self.error = Some(error);
+            return false;
+        }
+        self.databases[self.current].matches += 1;
+        if let Some(remaining) = &mut self.remaining {
+            *remaining -= 1;
+        }
+        !self.is_full()
+    }
+
+    fn write_record(&mut self, record: &Record) -> io::Result<()> {
+        if matches!(self.format, Format::Json) {
+            self.writer
+                .write_all(if self.records == 0 { b"[\n" } else { b",\n" })?;
+        }
+        serde_json::to_writer(&mut self.writer, record)?;
+        if matches!(self.format, Format::JsonLines) {
+            self.writer.write_all(b"\n")?;
+        }
+        self.records += 1;
+        Ok(())
+    }
+
+    /// Writes the sorted path names, if any.
+    fn drain_sorter(&mut self) -> io::Result<()> {
+        if let Some(sorter) = self.sorter.take() {
+            // Sorting by path name makes duplicates adjacent
+            let adjacent_unique = self.unique && self.seen.is_none();
+            let mut previous = String::new();
+            sorter.finish(|item| {
+                if adjacent_unique {
+                    if item.path() == previous {
+                        return true;
+                    }
+                    previous.clear();
+                    previous.push_str(item.path());
+                }
+                self.current = item.db;
+                self.emit(item.path(), &Entry::parse(item.line()))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Writes the sorted path names if any, then the summary of the query if
+    /// requested, and terminates the output. Returns whether the commands
+    /// run on the path names, if any, succeeded, or the error which stopped
+    /// the output.
+    pub fn finish(mut self) -> io::Result<bool> {
+        self.drain_sorter()?;
+        if let Some(visited) = self.visited.take() {
+            if let Err(error) = history::record(&visited) {
+                eprintln!("Cannot record the history: {}", error);
+            }
+        }
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        if self.summary {
+            let databases = std::mem::take(&mut self.databases);
+            self.write_record(&Record::Summary {
+                matches: databases.iter().map(|db| db.matches).sum(),
+                databases: databases
+                    .iter()
+                    .map(|db| DatabaseCount {
+                        name: &db.name,
+                        matches: db.matches,
+                    })
+                    .collect(),
+                elapsed_ms: self.started.elapsed().as_millis() as u64,
+            })?;
+        }
+        if matches!(self.format, Format::Json) {
+            self.writer
+                .write_all(if self.records == 0 { b"[]\n" } else { b"\n]\n" })?;
+        }
+        self.writer.flush()?;
+        Ok(match self.executor.take() {
+            Some(executor) => executor.finish(),
+            None => true,
+        })
+    }
+}
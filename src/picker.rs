@@ -0,0 +1,458 @@
+/*
+ * This file is part of Lolcate.
+ *
+ * Copyright © 2019 Nicolas Girard
+ *
+ * Lolcate is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Lolcate is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Lolcate.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Full-screen picker of `--interactive`, drawn on the terminal rather than
+//! on the standard output, so that the chosen path names can be piped.
+
+use crate::entry::{Entry, Kind};
+use crate::output::Candidate;
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::style::{Attribute, Print, SetAttribute};
+use crossterm::terminal::{self, ClearType};
+use crossterm::{cursor, execute, queue};
+use regex::Regex;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process;
+
+/// Number of bytes of a file shown in the preview pane.
+const PREVIEW_LEN: u64 = 64 * 1024;
+
+type Tty = io::BufWriter<fs::File>;
+
+/// Narrowest terminal showing the preview pane.
+const PREVIEW_MIN_WIDTH: u16 = 60;
+
+pub struct Picker<'a, F> {
+    candidates: &'a [Candidate],
+    db_names: &'a [String],
+    compile: F,
+    query: String,
+    /// Indices of the candidates matching the query
+    matches: Vec<usize>,
+    /// Index of the selected match
+    selected: usize,
+    /// Index of the first match shown
+    offset: usize,
+    /// Indices of the marked candidates
+    marked: BTreeSet<usize>,
+    preview: Option<(usize, Vec<String>)>,
+    /// Indices of the candidates opened using Ctrl-O
+    opened: Vec<usize>,
+    status: String,
+}
+
+/// What was done in the picker.
+pub struct Outcome {
+    /// Indices of the chosen candidates, or None if the picker was cancelled
+    pub chosen: Option<Vec<usize>>,
+    /// Indices of the candidates opened using Ctrl-O
+    pub opened: Vec<usize>,
+}
+
+impl<'a, F> Picker<'a, F>
+where
+    F: Fn(&str) -> Result<Regex, regex::Error>,
+{
+    /// `compile` builds the regex of each of the words of the query.
+    pub fn new(candidates: &'a [Candidate], db_names: &'a [String], compile: F) -> Picker<'a, F> {
+        Picker {
+            candidates,
+            db_names,
+            compile,
+            query: String::new(),
+            matches: (0..candidates.len()).collect(),
+            selected: 0,
+            offset: 0,
+            marked: BTreeSet::new(),
+            preview: None,
+            opened: Vec::new(),
+            status: String::new(),
+        }
+    }
+
+    /// Runs the picker, and returns the candidates chosen and opened.
+    pub fn run(mut self) -> io::Result<Outcome> {
+        let mut tty = match fs::OpenOptions::new().write(true).open("/dev/tty") {
+            Ok(tty) => io::BufWriter::new(tty),
+            Err(error) => {
+                eprintln!("The interactive mode requires a terminal: {}", error);
+                process::exit(1);
+            }
+        };
+        terminal::enable_raw_mode()?;
+        execute!(tty, terminal::EnterAlternateScreen, cursor::Hide)?;
+        let result = self.event_loop(&mut tty);
+        execute!(tty, cursor::Show, terminal::LeaveAlternateScreen)?;
+        terminal::disable_raw_mode()?;
+        Ok(Outcome {
+            chosen: result?,
+            opened: self.opened,
+        })
+    }
+
+    fn event_loop(&mut self, tty: &mut Tty) -> io::Result<Option<Vec<usize>>> {
+        loop {
+            self.draw(tty)?;
+            let key = match event::read()? {
+                Event::Key(key) if key.kind != KeyEventKind::Release => key,
+                _ => continue,
+            };
+            self.status.clear();
+            match self.handle_key(key, tty)? {
+                Action::Continue => {}
+                Action::Accept => return Ok(Some(self.selection())),
+                Action::Cancel => return Ok(None),
+            }
+        }
+    }
+
+    fn handle_key(&mut self, key: KeyEvent, tty: &mut Tty) -> io::Result<Action> {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let page = terminal::size()?.1.saturating_sub(1).max(1) as usize;
+        match key.code {
+            KeyCode::Esc => return Ok(Action::Cancel),
+            KeyCode::Char('c') | KeyCode::Char('g') if ctrl => return Ok(Action::Cancel),
+            KeyCode::Enter => {
+                return Ok(match self.matches.is_empty() && self.marked.is_empty() {
+                    true => Action::Continue,
+                    false => Action::Accept,
+                })
+            }
+            KeyCode::Up => self.move_by(-1),
+            KeyCode::Char('p') | KeyCode::Char('k') if ctrl => self.move_by(-1),
+            KeyCode::Down => self.move_by(1),
+            KeyCode::Char('n') | KeyCode::Char('j') if ctrl => self.move_by(1),
+            KeyCode::PageUp => self.move_by(-(page as isize)),
+            KeyCode::PageDown => self.move_by(page as isize),
+            KeyCode::Tab => {
+                if let Some(&candidate) = self.matches.get(self.selected) {
+                    if !self.marked.remove(&candidate) {
+                        self.marked.insert(candidate);
+                    }
+                    self.move_by(1);
+                }
+            }
+            KeyCode::Char('o') if ctrl => self.open(),
+            KeyCode::Char('y') if ctrl => self.copy(tty)?,
+            KeyCode::Char('u') if ctrl => {
+                self.query.clear();
+                self.update_matches();
+            }
+            KeyCode::Char('w') if ctrl => {
+                let end = self.query.trim_end().rfind(' ').map_or(0, |pos| pos + 1);
+                self.query.truncate(end);
+                self.update_matches();
+            }
+            KeyCode::Backspace => {
+                self.query.pop();
+                self.update_matches();
+            }
+            KeyCode::Char(c) if !ctrl => {
+                self.query.push(c);
+                self.update_matches();
+            }
+            _ => {}
+        }
+        Ok(Action::Continue)
+    }
+
+    fn path(&self, candidate: usize) -> &'a str {
+        self.candidates[candidate].path()
+    }
+
+    /// The marked candidates, or the selected one if none is marked.
+    fn selection(&self) -> Vec<usize> {
+        match self.marked.is_empty() {
+            true => self.matches.get(self.selected).copied().into_iter().collect(),
+            false => self.marked.iter().copied().collect(),
+        }
+    }
+
+    fn move_by(&mut self, delta: isize) {
+        if self.matches.is_empty() {
+            return;
+        }
+        let last = self.matches.len() as isize - 1;
+        self.selected = (self.selected as isize + delta).clamp(0, last) as usize;
+    }
+
+    /// Filters the candidates using the same rules as the patterns given on
+    /// the command line.
+    fn update_matches(&mut self) {
+        let patterns: Result<Vec<Regex>, _> =
+            self.query.split_whitespace().map(|p| (self.compile)(p)).collect();
+        let patterns = match patterns {
+            Ok(patterns) => patterns,
+            Err(_) => {
+                self.status = "Invalid regex".to_string();
+                return;
+            }
+        };
+        self.matches = (0..self.candidates.len())
+            .filter(|&i| {
+                let path = self.path(i);
+                patterns.iter().all(|re| re.is_match(path))
+            })
+            .collect();
+        self.selected = 0;
+        self.offset = 0;
+    }
+
+    fn open(&mut self) {
+        let candidate = match self.matches.get(self.selected) {
+            Some(&candidate) => candidate,
+            None => return,
+        };
+        let path = self.path(candidate);
+        let opener = if cfg!(target_os = "macos") {
+            "open"
+        } else {
+            "xdg-open"
+        };
+        self.status = match process::Command::new(opener)
+            .arg(path)
+            .stdin(process::Stdio::null())
+            .stdout(process::Stdio::null())
+            .stderr(process::Stdio::null())
+            .spawn()
+        {
+            Ok(_) => {
+                self.opened.push(candidate);
+                format!("Opened {}", printable(path))
+            }
+            Err(error) => format!("Cannot run {}: {}", opener, error),
+        };
+    }
+
+    /// Copies the selection to the clipboard, using the OSC 52 escape
+    /// sequence understood by most terminals, even over SSH.
+    fn copy(&mut self, tty: &mut Tty) -> io::Result<()> {
+        let selection = self.selection();
+        let text = selection
+            .iter()
+            .map(|&candidate| self.path(candidate))
+            .collect::<Vec<_>>()
+            .join("\n");
+        write!(tty, "\x1b]52;c;{}\x07", base64(text.as_bytes()))?;
+        self.status = match selection.len() {
+            1 => "Copied 1 path name".to_string(),
+            n => format!("Copied {} path names", n),
+        };
+        Ok(())
+    }
+
+    fn draw(&mut self, tty: &mut Tty) -> io::Result<()> {
+        let (width, height) = terminal::size()?;
+        let (width, height) = (width as usize, height as usize);
+        let list_height = height.saturating_sub(1);
+        let list_width = match width >= PREVIEW_MIN_WIDTH as usize {
+            true => width / 2,
+            false => width,
+        };
+        if self.selected < self.offset {
+            self.offset = self.selected;
+        } else if list_height > 0 && self.selected >= self.offset + list_height {
+            self.offset = self.selected + 1 - list_height;
+        }
+        for row in 0..list_height {
+            // Clearing line by line avoids flickering
+            queue!(tty, cursor::MoveTo(0, row as u16), terminal::Clear(ClearType::CurrentLine))?;
+            let candidate = match self.matches.get(self.offset + row) {
+                Some(&candidate) => candidate,
+                None => continue,
+            };
+            let marker = match self.marked.contains(&candidate) {
+                true => "* ",
+                false => "  ",
+            };
+            let path = printable(self.path(candidate));
+            let line = format!("{}{}", marker, truncate_start(&path, list_width.saturating_sub(2)));
+            if self.offset + row == self.selected {
+                queue!(
+                    tty,
+                    SetAttribute(Attribute::Reverse),
+                    Print(format!("{:<1$}", line, list_width)),
+                    SetAttribute(Attribute::Reset)
+                )?;
+            } else {
+                queue!(tty, Print(line))?;
+            }
+        }
+        if list_width < width {
+            let preview = self.preview_lines(list_height);
+            let preview_width = width - list_width - 2;
+            for row in 0..list_height {
+                queue!(tty, cursor::MoveTo(list_width as u16, row as u16), Print("│ "))?;
+                if let Some(line) = preview.get(row) {
+                    queue!(tty, Print(truncate_end(line, preview_width)))?;
+                }
+            }
+        }
+        let counter = match self.status.is_empty() {
+            true => format!("{}/{}", self.matches.len(), self.candidates.len()),
+            false => self.status.clone(),
+        };
+        let prompt = truncate_start(&format!("> {}", self.query), width.saturating_sub(counter.chars().count() + 1));
+        queue!(
+            tty,
+            cursor::MoveTo(0, height.saturating_sub(1) as u16),
+            terminal::Clear(ClearType::CurrentLine),
+            Print(&prompt),
+            cursor::MoveTo(width.saturating_sub(counter.chars().count()) as u16, height.saturating_sub(1) as u16),
+            Print(&counter)
+        )?;
+        tty.flush()
+    }
+
+    fn preview_lines(&mut self, height: usize) -> Vec<String> {
+        let candidate = match self.matches.get(self.selected) {
+            Some(&candidate) => candidate,
+            None => return Vec::new(),
+        };
+        match &self.preview {
+            Some((previewed, lines)) if *previewed == candidate => lines.clone(),
+            _ => {
+                let lines = self.preview(candidate, height);
+                self.preview = Some((candidate, lines.clone()));
+                lines
+            }
+        }
+    }
+
+    /// Describes a candidate, then shows the contents of its directory or
+    /// the beginning of its file.
+    fn preview(&self, candidate: usize, height: usize) -> Vec<String> {
+        let entry = Entry::parse(&self.candidates[candidate].line);
+        let mut header = vec![self.db_names[self.candidates[candidate].db].clone()];
+        if let Some(kind) = entry.kind() {
+            header.push(kind.as_str().to_string());
+        }
+        if let Some(size) = entry.size() {
+            header.push(format!("{} bytes", size));
+        }
+        if let Some(mime) = entry.mime() {
+            header.push(mime.to_string());
+        }
+        let mut lines = vec![printable(&header.join(", ")), String::new()];
+        let path = Path::new(entry.path);
+        let is_dir = match entry.kind() {
+            Some(kind) => kind == Kind::Dir || (kind == Kind::Symlink && path.is_dir()),
+            None => path.is_dir(),
+        };
+        if is_dir {
+            match fs::read_dir(path) {
+                Ok(entries) => {
+                    let mut names: Vec<String> = entries
+                        .filter_map(|e| e.ok())
+                        .map(|e| {
+                            let name = printable(&e.file_name().to_string_lossy());
+                            match e.file_type().is_ok_and(|t| t.is_dir()) {
+                                true => name + "/",
+                                false => name,
+                            }
+                        })
+                        .collect();
+                    names.sort();
+                    lines.extend(names);
+                }
+                Err(error) => lines.push(format!("({})", error)),
+            }
+        } else {
+            let mut buffer = Vec::new();
+            match fs::File::open(path).and_then(|f| f.take(PREVIEW_LEN).read_to_end(&mut buffer)) {
+                Ok(_) if buffer.contains(&0) => lines.push("(binary file)".to_string()),
+                Ok(_) => lines.extend(
+                    String::from_utf8_lossy(&buffer)
+                        .lines()
+                        .take(height)
+                        .map(|line| printable(&line.replace('\t', "    "))),
+                ),
+                Err(error) => lines.push(format!("({})", error)),
+            }
+        }
+        lines.truncate(height);
+        lines
+    }
+}
+
+enum Action {
+    Continue,
+    Accept,
+    Cancel,
+}
+
+/// Replaces the control characters of `s` with `?`, like ls, so that file
+/// names and contents can't send escape sequences to the terminal.
+fn printable(s: &str) -> String {
+    s.replace(|c: char| c.is_control(), "?")
+}
+
+/// Keeps the end of `s`, which holds the base name of path names.
+fn truncate_start(s: &str, width: usize) -> String {
+    let len = s.chars().count();
+    match len > width {
+        true if width > 0 => {
+            let tail: String = s.chars().skip(len - width + 1).collect();
+            format!("…{}", tail)
+        }
+        true => String::new(),
+        false => s.to_string(),
+    }
+}
+
+fn truncate_end(s: &str, width: usize) -> String {
+    s.chars().take(width).collect()
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => out.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 63] as char),
+                false => out.push('='),
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn printable_replaces_control_characters() {
+        assert_eq!(printable("a\x1b]52;c;eA==\x07b"), "a?]52;c;eA==?b");
+        assert_eq!(printable("/tmp/é"), "/tmp/é");
+    }
+
+    #[test]
+    fn truncate_start_keeps_the_end() {
+        assert_eq!(truncate_start("/a/b/c", 10), "/a/b/c");
+        assert_eq!(truncate_start("/a/b/c", 4), "…b/c");
+        assert_eq!(truncate_start("/a/b/c", 1), "…");
+        assert_eq!(truncate_start("/a/b/c", 0), "");
+    }
+}
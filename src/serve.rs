@@ -0,0 +1,515 @@
+/*
+ * This file is part of Lolcate.
+ *
+ * Copyright © 2019 Nicolas Girard
+ *
+ * Lolcate is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Lolcate is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Lolcate.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! A small HTTP server answering queries with JSON, for local tools.
+//!
+//! `GET /search` takes the `q` (repeatable), `db`, `all`, `type`, `mime`,
+//! `limit` and `ignore_case` parameters, and answers like `--json --summary`,
+//! with at most `DEFAULT_LIMIT` matches unless a limit is given.
+//! `GET /databases` describes the databases, groups and file types like
+//! `--info`.
+//!
+//! Unless `--allow-remote` is given, requests must name a loopback host in
+//! their `Host` header, so that web pages can't reach the server through DNS
+//! rebinding.
+
+use crate::output::{Format, Output};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, BufRead, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::process;
+use std::rc::Rc;
+use std::str;
+use std::thread;
+use std::time::Duration;
+
+/// Maximum length of the request line and of each header.
+const MAX_LINE_LEN: u64 = 8192;
+
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum number of matches of a search, unless the request or the
+/// defaults give a limit, as responses are buffered.
+const DEFAULT_LIMIT: usize = 1000;
+
+/// An HTTP error response.
+struct HttpError {
+    status: &'static str,
+    message: String,
+}
+
+impl HttpError {
+    fn bad_request(message: String) -> HttpError {
+        HttpError {
+            status: "400 Bad Request",
+            message,
+        }
+    }
+
+    /// An error of the server, e.g. an invalid configuration file.
+    fn internal(message: String) -> HttpError {
+        HttpError {
+            status: "500 Internal Server Error",
+            message,
+        }
+    }
+}
+
+impl From<io::Error> for HttpError {
+    fn from(error: io::Error) -> HttpError {
+        HttpError {
+            status: "500 Internal Server Error",
+            message: error.to_string(),
+        }
+    }
+}
+
+/// A writer appending to a buffer which can still be read once the writer
+/// has been given away.
+#[derive(Clone, Default)]
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'a str,
+}
+
+#[derive(Serialize)]
+struct VolumeInfo {
+    name: String,
+    /// Where the volume is mounted, if it is
+    mount_point: Option<String>,
+}
+
+#[derive(Serialize)]
+struct DatabaseInfo {
+    name: String,
+    description: String,
+    config_file: String,
+    ignores_file: Option<String>,
+    data_file: String,
+    in_all: bool,
+    volume: Option<VolumeInfo>,
+    /// End of the last update, in seconds since the Unix epoch
+    last_update: Option<i64>,
+    /// Whether the database wasn't updated within the stale_after interval
+    stale: bool,
+}
+
+#[derive(Serialize)]
+struct TypeInfo {
+    name: String,
+    definition: String,
+    /// The database defining the type, or none for the global ones
+    database: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Databases {
+    config_file: String,
+    databases: Vec<DatabaseInfo>,
+    groups: HashMap<String, Vec<String>>,
+    types: Vec<TypeInfo>,
+}
+
+/// Decodes a component of a query string.
+fn percent_decode(component: &str) -> Option<String> {
+    let bytes = component.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' => {
+                let hex = str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+                out.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 2;
+            }
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8(out).ok()
+}
+
+fn parse_query(query: &str) -> Result<Vec<(String, String)>, HttpError> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            match (percent_decode(name), percent_decode(value)) {
+                (Some(name), Some(value)) => Ok((name, value)),
+                _ => Err(HttpError::bad_request(format!("Invalid parameter: {}", pair))),
+            }
+        })
+        .collect()
+}
+
+fn parse_bool(name: &str, value: &str) -> Result<bool, HttpError> {
+    match value {
+        "" | "1" | "true" => Ok(true),
+        "0" | "false" => Ok(false),
+        _ => Err(HttpError::bad_request(format!("Invalid {}: {}", name, value))),
+    }
+}
+
+fn write_headers(stream: &mut TcpStream, status: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nConnection: close\r\n\r\n",
+        status
+    )
+}
+
+fn write_json<T: Serialize>(stream: &mut TcpStream, status: &str, body: &T) -> io::Result<()> {
+    write_headers(stream, status)?;
+    serde_json::to_writer(&mut *stream, body)?;
+    stream.write_all(b"\n")
+}
+
+fn search(mut stream: TcpStream, params: Vec<(String, String)>) -> Result<(), HttpError> {
+    let global_config = crate::read_global_config(&crate::global_config_fn())
+        .map_err(HttpError::internal)?;
+    let mut patterns = Vec::new();
+    let mut db_names = None;
+    let mut all = false;
+    let mut type_names = Vec::new();
+    let mut mime = None;
+    let mut limit = global_config.defaults.limit.or(Some(DEFAULT_LIMIT));
+    let mut ignore_case = global_config.defaults.ignore_case;
+    for (name, value) in &params {
+        match name.as_str() {
+            "q" => patterns.push(value.as_str()),
+            "db" => db_names = Some(value.as_str()),
+            "all" => all = parse_bool(name, value)?,
+            "type" => type_names.extend(value.split(',').filter(|n| !n.is_empty())),
+            "mime" => mime = Some(value.as_str()),
+            "limit" => {
+                limit = Some(value.parse().map_err(|_| {
+                    HttpError::bad_request(format!("Invalid limit: {}", value))
+                })?)
+            }
+            "ignore_case" => ignore_case = Some(parse_bool(name, value)?),
+            _ => return Err(HttpError::bad_request(format!("Unknown parameter: {}", name))),
+        }
+    }
+    let db_names = match (all, db_names) {
+        (true, _) => crate::all_databases().map_err(HttpError::internal)?,
+        (false, Some(db_names)) => crate::resolve_databases(db_names, &global_config.groups)
+            .map_err(HttpError::bad_request)?,
+        (false, None) => crate::resolve_databases(
+            &crate::default_database_name(&global_config.defaults),
+            &global_config.groups,
+        )
+        .map_err(HttpError::bad_request)?,
+    };
+    // Invalid configuration files are the server's fault, unlike the other
+    // errors of searched_databases
+    for db_name in &db_names {
+        let config_fn = crate::config_fn(db_name);
+        if config_fn.exists() {
+            crate::read_db_config(&config_fn).map_err(HttpError::internal)?;
+        }
+    }
+    let patterns_re = patterns
+        .iter()
+        .map(|pattern| crate::compile_regex(pattern, ignore_case))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|error| HttpError::bad_request(format!("Invalid regex: {}", error)))?;
+    let mime_filter = mime
+        .map(crate::mime_filter)
+        .transpose()
+        .map_err(HttpError::bad_request)?;
+    let databases = crate::searched_databases(db_names, &type_names, &global_config.types)
+        .map_err(HttpError::bad_request)?;
+
+    // The response is buffered so that errors can still be reported with
+    // an error status
+    let buffer = SharedBuffer::default();
+    let mut output = Output::to_writer(Box::new(buffer.clone()), Format::Json, limit, true);
+    crate::lookup_databases(
+        &databases,
+        &patterns_re,
+        mime_filter.as_ref(),
+        None,
+        &mut output,
+    )?;
+    output.finish()?;
+    write_headers(&mut stream, "200 OK")?;
+    stream.write_all(&buffer.0.borrow())?;
+    Ok(())
+}
+
+fn databases() -> Result<Databases, HttpError> {
+    let global_config = crate::read_global_config(&crate::global_config_fn())
+        .map_err(HttpError::internal)?;
+    let mut types: Vec<TypeInfo> = global_config
+        .types
+        .iter()
+        .map(|(name, def)| TypeInfo {
+            name: name.clone(),
+            definition: def.to_string(),
+            database: None,
+        })
+        .collect();
+    let stale_after = global_config
+        .defaults
+        .stale_after
+        .as_deref()
+        .map(crate::config::parse_interval)
+        .transpose()
+        .map_err(HttpError::internal)?;
+    let now = chrono::Local::now().timestamp();
+    let mut databases = Vec::new();
+    for db_name in crate::database_names(crate::lolcate_config_path()) {
+        let last_update = crate::update_log::last_update_time(&db_name);
+        let config_fn = crate::config_fn(&db_name);
+        let config = crate::read_db_config(&config_fn).map_err(HttpError::internal)?;
+        for (name, def) in &config.types {
+            types.push(TypeInfo {
+                name: name.clone(),
+                definition: def.to_string(),
+                database: Some(db_name.clone()),
+            });
+        }
+        databases.push(DatabaseInfo {
+            description: config.description.clone(),
+            config_file: config_fn.display().to_string(),
+            ignores_file: Some(crate::ignores_fn(&db_name))
+                .filter(|ignores_fn| ignores_fn.exists())
+                .map(|ignores_fn| ignores_fn.display().to_string()),
+            data_file: crate::db_fn(&db_name).display().to_string(),
+            in_all: !config.hide_from_all,
+            volume: config.volume.as_ref().map(|volume| VolumeInfo {
+                name: volume.to_string(),
+                mount_point: crate::mounts::volume_mount_point(volume)
+                    .map(|mount_point| mount_point.display().to_string()),
+            }),
+            last_update,
+            stale: stale_after
+                .is_some_and(|seconds| crate::update_log::is_stale(last_update, seconds, now)),
+            name: db_name,
+        });
+    }
+    types.sort_by(|a, b| (&a.name, &a.database).cmp(&(&b.name, &b.database)));
+    Ok(Databases {
+        config_file: crate::global_config_fn().display().to_string(),
+        databases,
+        groups: global_config.groups,
+        types,
+    })
+}
+
+/// The parts of a request which are used.
+struct Request {
+    method: String,
+    target: String,
+    /// The value of the Host header
+    host: Option<String>,
+}
+
+/// Reads the request line and the headers of a request.
+fn read_request(stream: &TcpStream) -> io::Result<Request> {
+    let mut reader = io::BufReader::new(stream);
+    let mut request_line = String::new();
+    (&mut reader).take(MAX_LINE_LEN).read_line(&mut request_line)?;
+    let mut host = None;
+    let mut header = String::new();
+    loop {
+        header.clear();
+        if (&mut reader).take(MAX_LINE_LEN).read_line(&mut header)? == 0
+            || header.trim_end().is_empty()
+        {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("host") {
+                host = Some(value.trim().to_string());
+            }
+        }
+    }
+    let mut parts = request_line.split_whitespace();
+    Ok(Request {
+        method: parts.next().unwrap_or_default().to_string(),
+        target: parts.next().unwrap_or_default().to_string(),
+        host,
+    })
+}
+
+/// Returns whether `host`, the value of a Host header, names the local
+/// machine, i.e. is `localhost` or a loopback address, with or without a port.
+fn is_local_host(host: &str) -> bool {
+    let name = match host.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or_default(),
+        None => host.rsplit_once(':').map_or(host, |(name, _)| name),
+    };
+    name.eq_ignore_ascii_case("localhost")
+        || name.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+fn handle(mut stream: TcpStream, allow_remote: bool) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let request = read_request(&stream)?;
+    let (method, target) = (&request.method, &request.target);
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let result = match (method.as_str(), path) {
+        _ if !allow_remote && !request.host.as_deref().is_some_and(is_local_host) => {
+            Err(HttpError {
+                status: "403 Forbidden",
+                message: format!(
+                    "Unexpected host: {}",
+                    request.host.as_deref().unwrap_or_default()
+                ),
+            })
+        }
+        ("GET", "/search") => {
+            parse_query(query).and_then(|params| search(stream.try_clone()?, params))
+        }
+        ("GET", "/databases") => {
+            databases().and_then(|databases| Ok(write_json(&mut stream, "200 OK", &databases)?))
+        }
+        ("GET", _) => Err(HttpError {
+            status: "404 Not Found",
+            message: format!("Not found: {}", path),
+        }),
+        _ => Err(HttpError {
+            status: "405 Method Not Allowed",
+            message: format!("Method not allowed: {}", method),
+        }),
+    };
+    match result {
+        Ok(()) => Ok(()),
+        Err(error) => {
+            if error.status.starts_with('5') {
+                eprintln!("{} {}: {}", method, path, error.message);
+            }
+            write_json(&mut stream, error.status, &ErrorBody { error: &error.message })
+        }
+    }
+}
+
+pub fn serve(listen: &str, allow_remote: bool) -> io::Result<()> {
+    if let crate::Scope::System = crate::scope() {
+        eprintln!("The server only serves the databases of the current user.");
+        process::exit(1);
+    }
+    let addrs: Vec<SocketAddr> = match listen.to_socket_addrs() {
+        Ok(addrs) => addrs.collect(),
+        Err(error) => {
+            eprintln!("Invalid address {}: {}", listen, error);
+            process::exit(1);
+        }
+    };
+    if !allow_remote {
+        if let Some(addr) = addrs.iter().find(|addr| !addr.ip().is_loopback()) {
+            eprintln!(
+                "Refusing to listen on {}, which isn't a loopback address. Use --allow-remote to listen on it anyway.",
+                addr
+            );
+            process::exit(1);
+        }
+    }
+    let listener = TcpListener::bind(&addrs[..])?;
+    eprintln!("Listening on http://{}", listener.local_addr()?);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                thread::spawn(move || {
+                    // Clients going away in the middle of a response are fine
+                    let _ = handle(stream, allow_remote);
+                });
+            }
+            Err(error) => eprintln!("Cannot accept connection: {}", error),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_decode_decodes_escapes_and_plus() {
+        assert_eq!(percent_decode("a+b%2Fc%25").as_deref(), Some("a b/c%"));
+        assert_eq!(percent_decode("%C3%A9t%c3%a9").as_deref(), Some("été"));
+        assert_eq!(percent_decode("plain").as_deref(), Some("plain"));
+    }
+
+    #[test]
+    fn percent_decode_rejects_invalid_escapes() {
+        assert_eq!(percent_decode("%"), None);
+        assert_eq!(percent_decode("%4"), None);
+        assert_eq!(percent_decode("%zz"), None);
+        assert_eq!(percent_decode("%FF"), None);
+    }
+
+    #[test]
+    fn parse_query_splits_parameters() {
+        let params = parse_query("q=foo&q=bar+baz&db=a%2Cb&all&&limit=").ok().unwrap();
+        let expected = [
+            ("q", "foo"),
+            ("q", "bar baz"),
+            ("db", "a,b"),
+            ("all", ""),
+            ("limit", ""),
+        ];
+        assert_eq!(
+            params,
+            expected
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<Vec<_>>()
+        );
+        assert!(parse_query("").ok().unwrap().is_empty());
+    }
+
+    #[test]
+    fn parse_query_rejects_invalid_parameters() {
+        let error = parse_query("q=ok&q=%zz").err().unwrap();
+        assert_eq!(error.status, "400 Bad Request");
+        assert_eq!(error.message, "Invalid parameter: q=%zz");
+    }
+
+    #[test]
+    fn local_hosts() {
+        let local = ["localhost", "LOCALHOST:8080", "127.0.0.1", "127.0.0.1:80", "[::1]", "[::1]:80"];
+        for host in &local {
+            assert!(is_local_host(host), "{}", host);
+        }
+        let remote = ["", "example.com", "example.com:80", "localhost.example.com", "10.0.0.1:80", "[::2]"];
+        for host in &remote {
+            assert!(!is_local_host(host), "{}", host);
+        }
+    }
+}
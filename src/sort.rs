@@ -0,0 +1,391 @@
+/*
+ * This file is part of Lolcate.
+ *
+ * Copyright © 2019 Nicolas Girard
+ *
+ * Lolcate is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Lolcate is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Lolcate.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Sorting of the path names found by queries. When they don't fit in
+//! memory, sorted chunks are written to temporary files and merged.
+
+use crate::entry::Entry;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, Seek, Write};
+use std::path::Path;
+
+/// Size of the path names and metadata kept in memory before they are
+/// written to a temporary file.
+const BUFFER_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum SortKey {
+    Path,
+    Name,
+    Size,
+    Mtime,
+}
+
+impl SortKey {
+    pub fn parse(key: &str) -> Option<SortKey> {
+        match key {
+            "path" => Some(SortKey::Path),
+            "name" => Some(SortKey::Name),
+            "size" => Some(SortKey::Size),
+            "mtime" => Some(SortKey::Mtime),
+            _ => None,
+        }
+    }
+
+    /// Whether the items with the same path name end up next to each other.
+    pub fn groups_paths(self) -> bool {
+        self == SortKey::Path || self == SortKey::Name
+    }
+}
+
+/// A path name found by a query, along with its stored metadata.
+pub struct Item {
+    /// Index of the database it was found in
+    pub db: usize,
+    line: String,
+    path_len: usize,
+    value: Option<i64>,
+    /// Frecency score, when ranking
+    score: Option<f64>,
+}
+
+impl Item {
+    fn new(db: usize, line: String, key: SortKey, scores: Option<&HashMap<String, f64>>) -> Item {
+        let entry = Entry::parse(&line);
+        let path_len = entry.path.len();
+        let value = match key {
+            SortKey::Size => entry.size().map(|size| size as i64),
+            SortKey::Mtime => entry.mtime(),
+            SortKey::Path | SortKey::Name => None,
+        };
+        let score = scores.and_then(|scores| scores.get(entry.path).copied());
+        Item {
+            db,
+            line,
+            path_len,
+            value,
+            score,
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.line[..self.path_len]
+    }
+
+    /// The path name followed by its metadata fields, as in data files.
+    pub fn line(&self) -> &str {
+        &self.line
+    }
+
+    fn name(&self) -> &str {
+        let path = self.path();
+        Path::new(path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(path)
+    }
+}
+
+fn compare(key: SortKey, reverse: bool, a: &Item, b: &Item) -> Ordering {
+    // The highest scores come first, whatever the order
+    let ranking = match (a.score, b.score) {
+        (Some(a), Some(b)) => b.total_cmp(&a),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    };
+    let ordering = match key {
+        SortKey::Path => Ordering::Equal,
+        SortKey::Name => a.name().cmp(b.name()),
+        SortKey::Size | SortKey::Mtime => a.value.cmp(&b.value),
+    }
+    .then_with(|| a.path().cmp(b.path()))
+    .then_with(|| a.db.cmp(&b.db));
+    ranking.then(match reverse {
+        true => ordering.reverse(),
+        false => ordering,
+    })
+}
+
+/// A sorted chunk written to a temporary file.
+struct Chunk {
+    lines: io::Lines<io::BufReader<fs::File>>,
+    head: Option<Item>,
+}
+
+impl Chunk {
+    fn next(
+        &mut self,
+        key: SortKey,
+        scores: Option<&HashMap<String, f64>>,
+    ) -> io::Result<Option<Item>> {
+        let next = match self.lines.next() {
+            Some(line) => {
+                let line = line?;
+                let (db, line) = line.split_once(' ').unwrap_or_default();
+                let db = db.parse().unwrap_or_default();
+                Some(Item::new(db, line.to_string(), key, scores))
+            }
+            None => None,
+        };
+        Ok(std::mem::replace(&mut self.head, next))
+    }
+}
+
+pub struct Sorter {
+    key: SortKey,
+    reverse: bool,
+    /// Frecency scores of the path names, when ranking them
+    scores: Option<HashMap<String, f64>>,
+    items: Vec<Item>,
+    buffered: usize,
+    /// Size of the items kept in memory before spilling them to a chunk
+    buffer_size: usize,
+    chunks: Vec<fs::File>,
+}
+
+impl Sorter {
+    pub fn new(key: SortKey, reverse: bool) -> Sorter {
+        Sorter {
+            key,
+            reverse,
+            scores: None,
+            items: Vec::new(),
+            buffered: 0,
+            buffer_size: BUFFER_SIZE,
+            chunks: Vec::new(),
+        }
+    }
+
+    pub fn key(&self) -> SortKey {
+        self.key
+    }
+
+    /// Puts the path names having a frecency score first, the highest
+    /// scores first, before sorting the others.
+    pub fn rank(&mut self, scores: HashMap<String, f64>) {
+        self.scores = Some(scores);
+    }
+
+    /// Adds the path name `path`, followed by the metadata `fields` of its
+    /// entry, found in the database of index `db`.
+    pub fn push(&mut self, db: usize, path: &str, fields: &str) -> io::Result<()> {
+        let line = format!("{}{}", path, fields);
+        self.buffered += line.len();
+        self.items.push(Item::new(db, line, self.key, self.scores.as_ref()));
+        if self.buffered >= self.buffer_size {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn sort(&mut self) {
+        let (key, reverse) = (self.key, self.reverse);
+        self.items.sort_unstable_by(|a, b| compare(key, reverse, a, b));
+    }
+
+    /// Writes the sorted items to a temporary file.
+    fn spill(&mut self) -> io::Result<()> {
+        self.sort();
+        let mut writer = io::BufWriter::new(tempfile::tempfile()?);
+        for item in self.items.drain(..) {
+            writeln!(writer, "{} {}", item.db, item.line)?;
+        }
+        let mut file = writer.into_inner().map_err(|error| error.into_error())?;
+        file.rewind()?;
+        self.chunks.push(file);
+        self.buffered = 0;
+        Ok(())
+    }
+
+    /// Calls `f` with each item in order, until it returns false.
+    pub fn finish<F>(mut self, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(&Item) -> bool,
+    {
+        if self.chunks.is_empty() {
+            self.sort();
+            for item in &self.items {
+                if !f(item) {
+                    break;
+                }
+            }
+            return Ok(());
+        }
+        if !self.items.is_empty() {
+            self.spill()?;
+        }
+        let mut chunks = Vec::new();
+        for file in self.chunks.drain(..) {
+            let mut chunk = Chunk {
+                lines: io::BufReader::new(file).lines(),
+                head: None,
+            };
+            chunk.next(self.key, self.scores.as_ref())?;
+            chunks.push(chunk);
+        }
+        let (key, reverse) = (self.key, self.reverse);
+        loop {
+            // There are few chunks, so that finding the smallest head is cheap
+            let smallest = chunks
+                .iter()
+                .enumerate()
+                .filter_map(|(i, chunk)| chunk.head.as_ref().map(|head| (i, head)))
+                .min_by(|(_, a), (_, b)| compare(key, reverse, a, b))
+                .map(|(i, _)| i);
+            let item = match smallest {
+                Some(i) => chunks[i].next(key, self.scores.as_ref())?.unwrap(),
+                None => return Ok(()),
+            };
+            if !f(&item) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Entries given as (db, path, size), pushed in this order.
+    const ENTRIES: &[(usize, &str, u64)] = &[
+        (0, "/b/zeta", 30),
+        (1, "/a/alpha", 10),
+        (0, "/c/beta", 20),
+        (1, "/b/zeta", 5),
+        (0, "/a/gamma", 20),
+    ];
+
+    fn sorted(mut sorter: Sorter, spill: bool) -> Vec<(usize, String)> {
+        if spill {
+            // Each item gets a chunk of its own
+            sorter.buffer_size = 1;
+        }
+        for (db, path, size) in ENTRIES {
+            sorter.push(*db, path, &format!("\0s:{}", size)).unwrap();
+        }
+        assert_eq!(sorter.chunks.len(), if spill { ENTRIES.len() } else { 0 });
+        let mut items = Vec::new();
+        sorter
+            .finish(|item| {
+                items.push((item.db, item.path().to_string()));
+                true
+            })
+            .unwrap();
+        items
+    }
+
+    fn paths(items: &[(usize, String)]) -> Vec<&str> {
+        items.iter().map(|(_, path)| path.as_str()).collect()
+    }
+
+    #[test]
+    fn sort_by_path() {
+        for spill in [false, true] {
+            let items = sorted(Sorter::new(SortKey::Path, false), spill);
+            assert_eq!(
+                items,
+                vec![
+                    (1, "/a/alpha".to_string()),
+                    (0, "/a/gamma".to_string()),
+                    (0, "/b/zeta".to_string()),
+                    (1, "/b/zeta".to_string()),
+                    (0, "/c/beta".to_string()),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn sort_by_path_reversed() {
+        for spill in [false, true] {
+            let items = sorted(Sorter::new(SortKey::Path, true), spill);
+            assert_eq!(
+                items,
+                vec![
+                    (0, "/c/beta".to_string()),
+                    (1, "/b/zeta".to_string()),
+                    (0, "/b/zeta".to_string()),
+                    (0, "/a/gamma".to_string()),
+                    (1, "/a/alpha".to_string()),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn sort_by_name_and_size() {
+        for spill in [false, true] {
+            let items = sorted(Sorter::new(SortKey::Name, false), spill);
+            assert_eq!(
+                paths(&items),
+                ["/a/alpha", "/c/beta", "/a/gamma", "/b/zeta", "/b/zeta"]
+            );
+            // Equal sizes are sorted by path name
+            let items = sorted(Sorter::new(SortKey::Size, false), spill);
+            assert_eq!(
+                paths(&items),
+                ["/b/zeta", "/a/alpha", "/a/gamma", "/c/beta", "/b/zeta"]
+            );
+            let items = sorted(Sorter::new(SortKey::Size, true), spill);
+            assert_eq!(
+                paths(&items),
+                ["/b/zeta", "/c/beta", "/a/gamma", "/a/alpha", "/b/zeta"]
+            );
+        }
+    }
+
+    #[test]
+    fn ranked_items_come_first() {
+        for spill in [false, true] {
+            let mut sorter = Sorter::new(SortKey::Path, true);
+            let scores = [("/a/gamma", 1.0), ("/c/beta", 4.0)];
+            sorter.rank(scores.iter().map(|(p, s)| (p.to_string(), *s)).collect());
+            let items = sorted(sorter, spill);
+            assert_eq!(
+                paths(&items),
+                ["/c/beta", "/a/gamma", "/b/zeta", "/b/zeta", "/a/alpha"]
+            );
+        }
+    }
+
+    #[test]
+    fn finish_stops_when_asked() {
+        for spill in [false, true] {
+            let mut sorter = Sorter::new(SortKey::Path, false);
+            if spill {
+                sorter.buffer_size = 1;
+            }
+            for (db, path, _) in ENTRIES {
+                sorter.push(*db, path, "").unwrap();
+            }
+            let mut count = 0;
+            sorter
+                .finish(|_| {
+                    count += 1;
+                    count < 2
+                })
+                .unwrap();
+            assert_eq!(count, 2);
+        }
+    }
+}
@@ -0,0 +1,259 @@
+/*
+ * This file is part of Lolcate.
+ *
+ * Copyright © 2019 Nicolas Girard
+ *
+ * Lolcate is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Lolcate is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Lolcate.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Statistics of databases, computed from their data files and from the
+//! log of their updates.
+
+use crate::entry::{Entry, Kind};
+use crate::update_log::{self, KindCounts};
+use bstr::io::BufReadExt;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str;
+use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+
+/// Number of directories and extensions listed.
+const TOP_COUNT: usize = 10;
+
+/// Figures computed from a data file.
+#[derive(Default)]
+struct DataStats {
+    entries: u64,
+    uncompressed: u64,
+    /// Entries of each kind, when stored
+    kinds: KindCounts,
+    /// Number of entries directly in each directory
+    dirs: HashMap<String, u64>,
+    extensions: HashMap<String, u64>,
+    /// Number of entries below each root, the last one counting the entries
+    /// below none of them
+    roots: Vec<u64>,
+}
+
+impl DataStats {
+    fn add(&mut self, path: &str, entry: &Entry, roots: &[PathBuf]) {
+        self.entries += 1;
+        self.kinds.count(entry.kind());
+        let path = Path::new(path);
+        if let Some(parent) = path.parent().and_then(|parent| parent.to_str()) {
+            *self.dirs.entry(parent.to_string()).or_default() += 1;
+        }
+        if entry.kind() != Some(Kind::Dir) {
+            let extension = match path.extension().and_then(|ext| ext.to_str()) {
+                Some(extension) => extension.to_lowercase(),
+                None => "(none)".to_string(),
+            };
+            *self.extensions.entry(extension).or_default() += 1;
+        }
+        // The innermost root containing the path name
+        let root = roots
+            .iter()
+            .enumerate()
+            .filter(|(_, root)| path.starts_with(root))
+            .max_by_key(|(_, root)| root.as_os_str().len())
+            .map_or(roots.len(), |(i, _)| i);
+        self.roots[root] += 1;
+    }
+}
+
+/// The `n` largest counts of `counts`, largest first.
+fn top(counts: HashMap<String, u64>, n: usize) -> Vec<(String, u64)> {
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_unstable_by(|(a, m), (b, n)| n.cmp(m).then_with(|| a.cmp(b)));
+    counts.truncate(n);
+    counts
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", size, UNITS[unit]),
+    }
+}
+
+/// Describes the counts of each kind of entries, if known.
+fn kinds_description(kinds: &KindCounts) -> Option<String> {
+    if kinds.unknown > 0 {
+        return None;
+    }
+    let mut parts = vec![
+        format!("{} files", kinds.files),
+        format!("{} dirs", kinds.dirs),
+        format!("{} symlinks", kinds.symlinks),
+    ];
+    if kinds.others > 0 {
+        parts.push(format!("{} others", kinds.others));
+    }
+    Some(parts.join(", "))
+}
+
+/// Writes a field of the statistics, made of one or several lines.
+fn write_field(stdout: &mut StandardStream, label: &str, lines: &[String]) -> io::Result<()> {
+    for (i, line) in lines.iter().enumerate() {
+        let label = match i {
+            0 => format!("{}:", label),
+            _ => String::new(),
+        };
+        writeln!(stdout, "    {:<14}{}", label, line)?;
+    }
+    Ok(())
+}
+
+fn count_lines(counts: &[(String, u64)]) -> Vec<String> {
+    let width = counts
+        .iter()
+        .map(|(_, count)| count.to_string().len())
+        .max()
+        .unwrap_or(0);
+    counts
+        .iter()
+        .map(|(name, count)| format!("{:>width$}  {}", count, name, width = width))
+        .collect()
+}
+
+fn database_stats(db_name: &str, stdout: &mut StandardStream) -> io::Result<()> {
+    let data_file = match crate::database_file(db_name) {
+        Ok(data_file) => data_file,
+        Err(error) => {
+            writeln!(stdout, "    {}", error)?;
+            return Ok(());
+        }
+    };
+    let config_fn = crate::config_fn(db_name);
+    let config = match config_fn.exists() {
+        true => Some(crate::get_db_config(&config_fn)),
+        false => None,
+    };
+    // Path names of databases bound to a volume are stored relative to its
+    // root, so that they can only be matched to the roots when mounted
+    let volume_location = crate::db_volume_location(config.as_ref());
+    let roots = match (&config, &volume_location) {
+        (Some(config), Some(Ok(mount_point))) => config.root_paths(Some(Path::new(mount_point))),
+        (Some(config), None) => config.root_paths(None),
+        _ => Vec::new(),
+    };
+
+    let mut stats = DataStats {
+        roots: vec![0; roots.len() + 1],
+        ..DataStats::default()
+    };
+    let on_disk = fs::metadata(&data_file)?.len();
+    let reader = io::BufReader::new(lz4::Decoder::new(fs::File::open(&data_file)?)?);
+    let mut located_path = String::new();
+    reader.for_byte_line(|line| {
+        stats.uncompressed += line.len() as u64 + 1;
+        if let Ok(line) = str::from_utf8(line) {
+            let entry = Entry::parse(line);
+            let mut path = entry.path;
+            if let Some(Ok(mount_point)) = &volume_location {
+                located_path = crate::volume_absolute_path(path, mount_point);
+                path = &located_path;
+            }
+            stats.add(path, &entry, &roots);
+        }
+        Ok(true)
+    })?;
+
+    let update = update_log::last(db_name);
+    let kinds = match &update {
+        // The data file doesn't store the kinds, unless it stores metadata
+        Some(update) if stats.kinds.unknown > 0 => kinds_description(&update.kinds),
+        _ => kinds_description(&stats.kinds),
+    };
+    let entries = match kinds {
+        Some(kinds) => format!("{} ({})", stats.entries, kinds),
+        None => stats.entries.to_string(),
+    };
+    write_field(stdout, "Entries", &[entries])?;
+    let ratio = match on_disk {
+        0 => String::new(),
+        _ => format!(" (ratio {:.1})", stats.uncompressed as f64 / on_disk as f64),
+    };
+    write_field(
+        stdout,
+        "Size",
+        &[format!(
+            "{} on disk, {} uncompressed{}",
+            human_size(on_disk),
+            human_size(stats.uncompressed),
+            ratio
+        )],
+    )?;
+    let last_update = match &update {
+        Some(update) => format!(
+            "{} (took {})",
+            update_log::format_time(update.started),
+            update_log::format_duration(update.duration_ms)
+        ),
+        None => update_log::last_update_time(db_name)
+            .map_or_else(|| "unknown".to_string(), update_log::format_time),
+    };
+    write_field(stdout, "Last update", &[last_update])?;
+    write_field(stdout, "Largest dirs", &count_lines(&top(stats.dirs, TOP_COUNT)))?;
+    write_field(stdout, "Extensions", &count_lines(&top(stats.extensions, TOP_COUNT)))?;
+    match volume_location {
+        Some(Err(volume)) => {
+            write_field(stdout, "Roots", &[format!("(volume {} not mounted)", volume)])?;
+        }
+        _ if !roots.is_empty() => {
+            let mut counts: Vec<(String, u64)> = roots
+                .iter()
+                .map(|root| root.display().to_string())
+                .zip(stats.roots.iter().copied())
+                .collect();
+            let outside = stats.roots[roots.len()];
+            if outside > 0 {
+                counts.push(("(below no root)".to_string(), outside));
+            }
+            write_field(stdout, "Roots", &count_lines(&counts))?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+pub fn show_stats(db_names: Vec<String>, color: ColorChoice) -> io::Result<()> {
+    let mut stdout = StandardStream::stdout(color);
+    let mut section_spec = ColorSpec::new();
+    section_spec.set_fg(Some(Color::Cyan));
+    let mut entry_spec = ColorSpec::new();
+    entry_spec.set_fg(Some(Color::Green));
+    stdout.set_color(&section_spec)?;
+    match db_names.is_empty() {
+        true => writeln!(&mut stdout, "No databases found.")?,
+        false => writeln!(&mut stdout, "Statistics:")?,
+    }
+    stdout.reset()?;
+    for db_name in db_names {
+        stdout.set_color(&entry_spec)?;
+        writeln!(&mut stdout, "  {}", db_name)?;
+        stdout.reset()?;
+        database_stats(&db_name, &mut stdout)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,274 @@
+/*
+ * This file is part of Lolcate.
+ *
+ * Copyright © 2019 Nicolas Girard
+ *
+ * Lolcate is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Lolcate is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Lolcate.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Templates given to `--format`, such as `{db}\t{size}\t{mtime:%F}\t{path}`.
+
+use crate::entry::Entry;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{Local, TimeZone};
+use std::fmt::Write;
+use std::path::Path;
+
+const DEFAULT_TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+enum Placeholder {
+    Path,
+    Basename,
+    Dirname,
+    Extension,
+    Database,
+    /// The path name relative to the indexed directory containing it
+    RelativePath,
+    Kind,
+    Size,
+    Mtime(String),
+    Mime,
+}
+
+enum Part {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+/// What a template is rendered from.
+pub struct Context<'a> {
+    pub database: &'a str,
+    pub path: &'a str,
+    pub root: Option<&'a Path>,
+    pub entry: &'a Entry<'a>,
+}
+
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    /// Whether a format given to `--format` is a template rather than the
+    /// name of an output format.
+    pub fn is_template(format: &str) -> bool {
+        format.contains(['{', '}', '\\'])
+    }
+
+    pub fn parse(template: &str) -> Result<Template, String> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some('t') => literal.push('\t'),
+                    Some('n') => literal.push('\n'),
+                    Some('0') => literal.push('\0'),
+                    Some('\\') => literal.push('\\'),
+                    Some(c) => return Err(format!("Unknown escape sequence \\{} in template", c)),
+                    None => literal.push('\\'),
+                },
+                '{' => {
+                    let rest = chars.as_str();
+                    if let Some(rest) = rest.strip_prefix('{') {
+                        literal.push('{');
+                        chars = rest.chars();
+                        continue;
+                    }
+                    let end = match rest.find('}') {
+                        Some(end) => end,
+                        None => return Err(format!("Unterminated placeholder in template: {}", template)),
+                    };
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Placeholder(placeholder(&rest[..end])?));
+                    chars = rest[end + 1..].chars();
+                }
+                '}' => {
+                    let rest = chars.as_str();
+                    match rest.strip_prefix('}') {
+                        Some(rest) => {
+                            literal.push('}');
+                            chars = rest.chars();
+                        }
+                        None => return Err(format!("Unmatched }} in template: {}", template)),
+                    }
+                }
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(Template { parts })
+    }
+
+    /// Whether rendering the template requires the indexed directories.
+    pub fn uses_root(&self) -> bool {
+        self.parts
+            .iter()
+            .any(|part| matches!(part, Part::Placeholder(Placeholder::RelativePath)))
+    }
+
+    pub fn render(&self, out: &mut String, context: &Context) {
+        let path = Path::new(context.path);
+        for part in &self.parts {
+            let placeholder = match part {
+                Part::Literal(literal) => {
+                    out.push_str(literal);
+                    continue;
+                }
+                Part::Placeholder(placeholder) => placeholder,
+            };
+            match placeholder {
+                Placeholder::Path => out.push_str(context.path),
+                Placeholder::Basename => match path.file_name() {
+                    Some(name) => out.push_str(&name.to_string_lossy()),
+                    None => out.push_str(context.path),
+                },
+                Placeholder::Dirname => {
+                    if let Some(parent) = path.parent() {
+                        out.push_str(&parent.to_string_lossy());
+                    }
+                }
+                Placeholder::Extension => {
+                    if let Some(extension) = path.extension() {
+                        out.push_str(&extension.to_string_lossy());
+                    }
+                }
+                Placeholder::Database => out.push_str(context.database),
+                Placeholder::RelativePath => {
+                    match context.root.and_then(|root| path.strip_prefix(root).ok()) {
+                        Some(relative) => out.push_str(&relative.to_string_lossy()),
+                        None => out.push_str(context.path),
+                    }
+                }
+                Placeholder::Kind => {
+                    if let Some(kind) = context.entry.kind() {
+                        out.push_str(kind.as_str());
+                    }
+                }
+                Placeholder::Size => {
+                    if let Some(size) = context.entry.size() {
+                        write!(out, "{}", size).unwrap();
+                    }
+                }
+                Placeholder::Mtime(format) => {
+                    if let Some(mtime) = context
+                        .entry
+                        .mtime()
+                        .and_then(|mtime| Local.timestamp_opt(mtime, 0).single())
+                    {
+                        write!(out, "{}", mtime.format(format)).unwrap();
+                    }
+                }
+                Placeholder::Mime => {
+                    if let Some(mime) = context.entry.mime() {
+                        out.push_str(mime);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn placeholder(name: &str) -> Result<Placeholder, String> {
+    if let Some(format) = name.strip_prefix("mtime:") {
+        if StrftimeItems::new(format).any(|item| item == Item::Error) {
+            return Err(format!("Invalid time format in template: {}", format));
+        }
+        return Ok(Placeholder::Mtime(format.to_string()));
+    }
+    Ok(match name {
+        "path" => Placeholder::Path,
+        "basename" => Placeholder::Basename,
+        "dirname" => Placeholder::Dirname,
+        "extension" | "ext" => Placeholder::Extension,
+        "db" => Placeholder::Database,
+        "relpath" => Placeholder::RelativePath,
+        "kind" => Placeholder::Kind,
+        "size" => Placeholder::Size,
+        "mtime" => Placeholder::Mtime(DEFAULT_TIME_FORMAT.to_string()),
+        "mime" => Placeholder::Mime,
+        _ => return Err(format!("Unknown placeholder {{{}}} in template", name)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str, line: &str, root: Option<&str>) -> String {
+        let entry = Entry::parse(line);
+        let mut out = String::new();
+        Template::parse(template).unwrap().render(
+            &mut out,
+            &Context {
+                database: "docs",
+                path: entry.path,
+                root: root.map(Path::new),
+                entry: &entry,
+            },
+        );
+        out
+    }
+
+    #[test]
+    fn is_template() {
+        assert!(Template::is_template("{path}"));
+        assert!(Template::is_template("a\\tb"));
+        assert!(!Template::is_template("json"));
+    }
+
+    #[test]
+    fn path_placeholders() {
+        let line = "/home/me/notes/todo.md";
+        assert_eq!(
+            render("{db}\\t{basename}\\t{dirname}\\t{ext}", line, None),
+            "docs\ttodo.md\t/home/me/notes\tmd"
+        );
+        assert_eq!(render("{relpath}", line, Some("/home/me")), "notes/todo.md");
+        assert_eq!(render("{relpath}", line, None), line);
+        assert_eq!(render("{extension}", "/home/me/Makefile", None), "");
+    }
+
+    #[test]
+    fn metadata_placeholders() {
+        let line = "/a/b.png\0m:image/png\0k:f\0s:42\0t:0";
+        assert_eq!(render("{kind} {size} {mime}", line, None), "file 42 image/png");
+        assert_eq!(render("{mtime:%s}", line, None), "0");
+        // Missing metadata renders as nothing
+        assert_eq!(render("[{size}{mime}{mtime}]", "/a/b", None), "[]");
+    }
+
+    #[test]
+    fn escapes_and_braces() {
+        assert_eq!(render("{{{path}}}\\n\\0\\\\", "/x", None), "{/x}\n\0\\");
+        assert_eq!(render("trailing\\", "/x", None), "trailing\\");
+    }
+
+    #[test]
+    fn invalid_templates() {
+        for template in ["{path", "path}", "{name}", "{mtime:%Q}", "\\q{path}"] {
+            assert!(Template::parse(template).is_err(), "{}", template);
+        }
+    }
+
+    #[test]
+    fn uses_root() {
+        assert!(Template::parse("{db}:{relpath}").unwrap().uses_root());
+        assert!(!Template::parse("{db}:{path}").unwrap().uses_root());
+    }
+}
@@ -0,0 +1,183 @@
+/*
+ * This file is part of Lolcate.
+ *
+ * Copyright © 2019 Nicolas Girard
+ *
+ * Lolcate is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Lolcate is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Lolcate.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::config::TypeDef;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use regex::{Regex, RegexBuilder};
+use std::collections::HashMap;
+
+/// A compiled file type, matching path names.
+pub struct FileType {
+    regex: Option<Regex>,
+    globs: GlobSet,
+    mimes: GlobSet,
+}
+
+impl FileType {
+    /// Whether a path name, whose MIME type might have been detected when
+    /// indexing, is of this type.
+    pub fn is_match(&self, path: &str, mime: Option<&str>) -> bool {
+        self.regex.as_ref().is_some_and(|re| re.is_match(path))
+            || self.globs.is_match(path)
+            || mime.is_some_and(|mime| self.mimes.is_match(mime))
+    }
+}
+
+/// The patterns a file type is made of, once the types it refers to have
+/// been resolved.
+#[derive(Default)]
+struct Patterns {
+    regexes: Vec<String>,
+    globs: Vec<String>,
+    mimes: Vec<String>,
+}
+
+fn extensions_regex(extensions: &[String]) -> String {
+    let extensions: Vec<String> = extensions
+        .iter()
+        .map(|e| regex::escape(e.trim_start_matches('.')))
+        .collect();
+    format!(r"\.(?i:{})$", extensions.join("|"))
+}
+
+fn collect(
+    name: &str,
+    types: &HashMap<String, TypeDef>,
+    stack: &mut Vec<String>,
+    patterns: &mut Patterns,
+) -> Result<(), String> {
+    if stack.iter().any(|n| n == name) {
+        return Err(format!(
+            "File type {} is defined in terms of itself ({} -> {}).",
+            name,
+            stack.join(" -> "),
+            name
+        ));
+    }
+    let def = match types.get(name) {
+        Some(def) => def,
+        None => {
+            return Err(match stack.last() {
+                Some(parent) => format!("Unknown file type {} used by type {}.", name, parent),
+                None => {
+                    let mut names: Vec<&String> = types.keys().collect();
+                    names.sort();
+                    let names: Vec<&str> = names.iter().map(|n| n.as_str()).collect();
+                    format!(
+                        "Unknown file type: {}. Known types are: {}.",
+                        name,
+                        names.join(", ")
+                    )
+                }
+            })
+        }
+    };
+    stack.push(name.to_string());
+    match def {
+        TypeDef::Regex(regex) => patterns.regexes.push(regex.clone()),
+        TypeDef::Types(names) => {
+            for n in names {
+                collect(n, types, stack, patterns)?;
+            }
+        }
+        TypeDef::Spec(spec) => {
+            if !spec.extensions.is_empty() {
+                patterns.regexes.push(extensions_regex(&spec.extensions));
+            }
+            patterns.globs.extend(spec.globs.iter().cloned());
+            patterns.regexes.extend(spec.regex.iter().cloned());
+            patterns.mimes.extend(spec.mime.iter().cloned());
+            for n in &spec.types {
+                collect(n, types, stack, patterns)?;
+            }
+        }
+    }
+    stack.pop();
+    Ok(())
+}
+
+/// Compiles the file type `name` defined in `types`.
+pub fn build(name: &str, types: &HashMap<String, TypeDef>) -> Result<FileType, String> {
+    let mut patterns = Patterns::default();
+    collect(name, types, &mut Vec::new(), &mut patterns)?;
+
+    let regex = match patterns.regexes.len() {
+        0 => None,
+        _ => {
+            // Check each regex separately in order to report the faulty one
+            for regex in &patterns.regexes {
+                if let Err(error) = Regex::new(regex) {
+                    return Err(format!("Invalid regex for file type {}: {}", name, error));
+                }
+            }
+            let alternatives: Vec<String> = patterns
+                .regexes
+                .iter()
+                .map(|r| format!("(?:{})", r))
+                .collect();
+            match RegexBuilder::new(&alternatives.join("|")).build() {
+                Ok(re) => Some(re),
+                Err(error) => {
+                    return Err(format!("Invalid regex for file type {}: {}", name, error))
+                }
+            }
+        }
+    };
+
+    let mut globs = GlobSetBuilder::new();
+    for glob in &patterns.globs {
+        // Relative globs may match at any depth, e.g. `*.rs` matches base names
+        let pattern = match glob.starts_with('/') || glob.starts_with("**") {
+            true => glob.clone(),
+            false => format!("**/{}", glob),
+        };
+        match Glob::new(&pattern) {
+            Ok(glob) => {
+                globs.add(glob);
+            }
+            Err(error) => return Err(format!("Invalid glob for file type {}: {}", name, error)),
+        }
+    }
+    let globs = match globs.build() {
+        Ok(globs) => globs,
+        Err(error) => return Err(format!("Invalid glob for file type {}: {}", name, error)),
+    };
+
+    let mut mimes = GlobSetBuilder::new();
+    for mime in &patterns.mimes {
+        match Glob::new(mime) {
+            Ok(glob) => {
+                mimes.add(glob);
+            }
+            Err(error) => {
+                return Err(format!("Invalid MIME type for file type {}: {}", name, error))
+            }
+        }
+    }
+    let mimes = match mimes.build() {
+        Ok(mimes) => mimes,
+        Err(error) => return Err(format!("Invalid MIME type for file type {}: {}", name, error)),
+    };
+
+    Ok(FileType {
+        regex,
+        globs,
+        mimes,
+    })
+}
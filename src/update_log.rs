@@ -0,0 +1,213 @@
+/*
+ * This file is part of Lolcate.
+ *
+ * Copyright © 2019 Nicolas Girard
+ *
+ * Lolcate is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Lolcate is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Lolcate.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Log of the updates of each database, with one JSON record per line.
+//!
+//! The log is rotated once it grows over `MAX_LOG_LEN` bytes, the previous
+//! records being kept in `updates.log.1` until the next rotation.
+
+use crate::entry::Kind;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Size of the log above which it is rotated.
+const MAX_LOG_LEN: u64 = 1024 * 1024;
+
+/// Size of the end of the log read to find the last record, much larger
+/// than a record.
+const TAIL_LEN: u64 = 64 * 1024;
+
+const HOUR: i64 = 3600;
+const DAY: i64 = 24 * HOUR;
+
+/// Numbers of entries of each kind.
+#[derive(Default, Serialize, Deserialize)]
+pub struct KindCounts {
+    pub files: u64,
+    pub dirs: u64,
+    pub symlinks: u64,
+    pub others: u64,
+    /// Entries kept from the previous index, whose kind isn't stored
+    pub unknown: u64,
+}
+
+impl KindCounts {
+    pub fn count(&mut self, kind: Option<Kind>) {
+        match kind {
+            Some(Kind::File) => self.files += 1,
+            Some(Kind::Dir) => self.dirs += 1,
+            Some(Kind::Symlink) => self.symlinks += 1,
+            Some(Kind::Other) => self.others += 1,
+            None => self.unknown += 1,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.files + self.dirs + self.symlinks + self.others + self.unknown
+    }
+}
+
+/// A record of the update log.
+#[derive(Default, Serialize, Deserialize)]
+pub struct UpdateRecord {
+    /// Start and end of the update, in seconds since the Unix epoch
+    pub started: i64,
+    pub ended: i64,
+    pub duration_ms: u64,
+    /// Number of entries written to the data file
+    pub entries: u64,
+    #[serde(flatten)]
+    pub kinds: KindCounts,
+    /// Path names left out since they aren't valid UTF-8
+    pub invalid_utf8: u64,
+    /// Path names left out since they contain a newline
+    pub newlines: u64,
+    /// Entries which couldn't be read, e.g. for lack of permissions
+    pub access_errors: u64,
+    /// Number of threads walking the directories
+    pub threads: usize,
+}
+
+fn log_fn(db_name: &str) -> PathBuf {
+    crate::db_data_dir(db_name).join("updates.log")
+}
+
+/// Appends `record` to the log of the database `db_name`, rotating it first
+/// if it grew too large.
+pub fn append(db_name: &str, record: &UpdateRecord) -> io::Result<()> {
+    let log_fn = log_fn(db_name);
+    if fs::metadata(&log_fn).is_ok_and(|metadata| metadata.len() > MAX_LOG_LEN) {
+        fs::rename(&log_fn, log_fn.with_extension("log.1"))?;
+    }
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_fn)?
+        .write_all(line.as_bytes())
+}
+
+/// Returns the record of the last update of the database `db_name`, if
+/// logged, reading only the end of the log.
+pub fn last(db_name: &str) -> Option<UpdateRecord> {
+    read_last(&log_fn(db_name))
+}
+
+fn read_last(log_fn: &Path) -> Option<UpdateRecord> {
+    let mut file = fs::File::open(log_fn).ok()?;
+    let start = file.metadata().ok()?.len().saturating_sub(TAIL_LEN);
+    file.seek(SeekFrom::Start(start)).ok()?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail).ok()?;
+    let tail = String::from_utf8_lossy(&tail);
+    let mut lines = tail.lines();
+    if start > 0 {
+        // Most likely the end of a record
+        lines.next();
+    }
+    lines
+        .rev()
+        .find_map(|line| serde_json::from_str(line).ok())
+}
+
+pub fn format_time(timestamp: i64) -> String {
+    match chrono::DateTime::from_timestamp(timestamp, 0) {
+        Some(time) => time
+            .with_timezone(&chrono::Local)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string(),
+        None => timestamp.to_string(),
+    }
+}
+
+pub fn format_duration(ms: u64) -> String {
+    match ms {
+        0..=999 => format!("{} ms", ms),
+        _ => format!("{:.1} s", ms as f64 / 1000.0),
+    }
+}
+
+/// Describes how long ago `timestamp` was, roughly.
+pub fn format_age(timestamp: i64, now: i64) -> String {
+    let age = now - timestamp;
+    let (count, unit) = match age {
+        _ if age < 60 => return "just now".to_string(),
+        _ if age < HOUR => (age / 60, "minute"),
+        _ if age < DAY => (age / HOUR, "hour"),
+        _ => (age / DAY, "day"),
+    };
+    match count {
+        1 => format!("1 {} ago", unit),
+        _ => format!("{} {}s ago", count, unit),
+    }
+}
+
+/// Whether a database last updated at `last_update`, if ever, is due for an
+/// update `stale_after` seconds later.
+pub fn is_stale(last_update: Option<i64>, stale_after: i64, now: i64) -> bool {
+    last_update.is_none_or(|time| now - time > stale_after)
+}
+
+/// Returns when the database `db_name` was last updated, in seconds since
+/// the Unix epoch, falling back to the modification time of its data file
+/// for the updates which weren't logged.
+pub fn last_update_time(db_name: &str) -> Option<i64> {
+    if let Some(record) = last(db_name) {
+        return Some(record.ended);
+    }
+    let modified = fs::metadata(crate::db_fn(db_name)).ok()?.modified().ok()?;
+    Some(chrono::DateTime::<chrono::Local>::from(modified).timestamp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(started: i64) -> String {
+        let record = UpdateRecord {
+            started,
+            ..UpdateRecord::default()
+        };
+        serde_json::to_string(&record).unwrap() + "\n"
+    }
+
+    #[test]
+    fn read_last_reads_the_last_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let log_fn = dir.path().join("updates.log");
+        assert!(read_last(&log_fn).is_none());
+
+        let mut log = String::new();
+        let mut started = 0;
+        while (log.len() as u64) < 3 * TAIL_LEN {
+            started += 1;
+            log.push_str(&record(started));
+        }
+        fs::write(&log_fn, &log).unwrap();
+        assert_eq!(read_last(&log_fn).unwrap().started, started);
+
+        // An interrupted write leaves a partial record behind
+        log.push_str("{\"started\":");
+        fs::write(&log_fn, &log).unwrap();
+        assert_eq!(read_last(&log_fn).unwrap().started, started);
+    }
+}
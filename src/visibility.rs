@@ -0,0 +1,129 @@
+/*
+ * This file is part of Lolcate.
+ *
+ * Copyright © 2019 Nicolas Girard
+ *
+ * Lolcate is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Lolcate is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Lolcate.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Visibility of the path names of system-wide databases.
+//!
+//! Like mlocate, a path name is only shown to a user who can list the
+//! directory containing it, which requires being able to traverse all the
+//! directories above it as well. The data files themselves are only
+//! readable by the `lolcate` group, which the `lolcate` command is installed
+//! setgid to, so that they can't be read bypassing this check.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Group owning the data files of the system-wide databases.
+pub const DATA_GROUP: &str = "lolcate";
+
+/// Gives up the privileges of the group `lolcate` is installed setgid to,
+/// once the data files of the system-wide databases are opened, or right
+/// away when they aren't needed.
+#[cfg(not(windows))]
+pub fn drop_privileges() {
+    let gid = unsafe { libc::getgid() };
+    if unsafe { libc::getegid() } == gid {
+        return;
+    }
+    // Setting the real group ID resets the saved one as well
+    if unsafe { libc::setregid(gid, gid) } != 0 {
+        eprintln!(
+            "Cannot drop the privileges of group {}: {}",
+            DATA_GROUP,
+            io::Error::last_os_error()
+        );
+        std::process::exit(1);
+    }
+}
+
+#[cfg(windows)]
+pub fn drop_privileges() {}
+
+#[cfg(not(windows))]
+fn group_id(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    let group = unsafe { libc::getgrnam(name.as_ptr()) };
+    match group.is_null() {
+        true => None,
+        false => Some(unsafe { (*group).gr_gid }),
+    }
+}
+
+/// Makes the data file of a system-wide database readable by its owner and
+/// the members of `DATA_GROUP` only.
+#[cfg(not(windows))]
+pub fn restrict_data_file(file: &fs::File) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    file.set_permissions(fs::Permissions::from_mode(0o640))?;
+    match group_id(DATA_GROUP) {
+        Some(gid) => std::os::unix::fs::fchown(file, None, Some(gid)),
+        None => {
+            eprintln!(
+                "Group {} doesn't exist: only root can query the system-wide databases.",
+                DATA_GROUP
+            );
+            Ok(())
+        }
+    }
+}
+
+#[cfg(windows)]
+pub fn restrict_data_file(_file: &fs::File) -> io::Result<()> {
+    Ok(())
+}
+
+/// Whether the current user can list the directory `dir`.
+#[cfg(not(windows))]
+fn can_list(dir: &Path) -> bool {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    match CString::new(dir.as_os_str().as_bytes()) {
+        // access(2) checks the real user and group IDs, even when running setgid
+        Ok(dir) => unsafe { libc::access(dir.as_ptr(), libc::R_OK | libc::X_OK) == 0 },
+        Err(_) => false,
+    }
+}
+
+#[cfg(windows)]
+fn can_list(_dir: &Path) -> bool {
+    true
+}
+
+/// Checks the visibility of path names, caching the result for each
+/// directory.
+#[derive(Default)]
+pub struct Visibility {
+    dirs: HashMap<PathBuf, bool>,
+}
+
+impl Visibility {
+    pub fn is_visible(&mut self, path: &str) -> bool {
+        let dir = match Path::new(path).parent() {
+            Some(dir) => dir,
+            None => return true,
+        };
+        if let Some(visible) = self.dirs.get(dir) {
+            return *visible;
+        }
+        let visible = can_list(dir);
+        self.dirs.insert(dir.to_path_buf(), visible);
+        visible
+    }
+}
@@ -0,0 +1,160 @@
+/*
+ * This file is part of Lolcate.
+ *
+ * Copyright © 2019 Nicolas Girard
+ *
+ * Lolcate is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Lolcate is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Lolcate.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Runs `lolcate serve` on a database indexing a temporary directory.
+
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::process::{Child, ChildStderr, Command, Stdio};
+
+const LOLCATE: &str = env!("CARGO_BIN_EXE_lolcate");
+
+/// A running server, killed when dropped.
+struct Server {
+    child: Child,
+    port: u16,
+    /// Kept open, as the server logs errors
+    _stderr: BufReader<ChildStderr>,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn lolcate(dir: &Path) -> Command {
+    let mut command = Command::new(LOLCATE);
+    command
+        .env("LOLCATE_CONFIG_DIR", dir.join("config"))
+        .env("LOLCATE_DATA_DIR", dir.join("data"))
+        .env_remove("LOLCATE_DB")
+        .stdin(Stdio::null());
+    command
+}
+
+/// Creates and updates the database "test", indexing `dir/tree`.
+fn create_database(dir: &Path) {
+    let tree = dir.join("tree");
+    fs::create_dir_all(tree.join("sub")).unwrap();
+    fs::write(tree.join("alpha.txt"), "").unwrap();
+    fs::write(tree.join("sub").join("beta.rs"), "").unwrap();
+    let db_config_dir = dir.join("config").join("test");
+    fs::create_dir_all(&db_config_dir).unwrap();
+    fs::write(
+        db_config_dir.join("config.toml"),
+        format!(
+            "description = \"Test\"\ndirs = [{:?}]\nignore_symlinks = false\nignore_hidden = false\n",
+            tree
+        ),
+    )
+    .unwrap();
+    let status = lolcate(dir)
+        .args(["update", "--db", "test"])
+        .stdout(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+fn start_server(dir: &Path) -> Server {
+    let mut child = lolcate(dir)
+        .args(["serve", "--listen", "127.0.0.1:0"])
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut line = String::new();
+    let mut stderr = BufReader::new(child.stderr.take().unwrap());
+    stderr.read_line(&mut line).unwrap();
+    let port = line
+        .trim_end()
+        .rsplit(':')
+        .next()
+        .and_then(|port| port.parse().ok())
+        .unwrap_or_else(|| panic!("Unexpected output: {}", line));
+    Server {
+        child,
+        port,
+        _stderr: stderr,
+    }
+}
+
+/// Sends a GET request and returns the status line and the body of the response.
+fn get(server: &Server, host: &str, target: &str) -> (String, serde_json::Value) {
+    let mut stream = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, host).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.lines().next().unwrap().to_string();
+    (status, serde_json::from_str(body).unwrap())
+}
+
+#[test]
+fn serve() {
+    let dir = tempfile::tempdir().unwrap();
+    create_database(dir.path());
+    let server = start_server(dir.path());
+    let host = format!("127.0.0.1:{}", server.port);
+
+    let (status, body) = get(&server, &host, "/search?db=test&q=beta");
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    let records = body.as_array().unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["type"], "match");
+    assert_eq!(records[0]["database"], "test");
+    assert_eq!(
+        records[0]["path"],
+        dir.path().join("tree/sub/beta.rs").to_str().unwrap()
+    );
+    assert_eq!(records[1]["type"], "summary");
+    assert_eq!(records[1]["matches"], 1);
+
+    let (status, body) = get(&server, "localhost", "/databases");
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    let databases = body["databases"].as_array().unwrap();
+    assert_eq!(databases.len(), 1);
+    assert_eq!(databases[0]["name"], "test");
+    assert_eq!(databases[0]["description"], "Test");
+    assert!(databases[0]["last_update"].is_i64());
+
+    let (status, body) = get(&server, &host, "/search?db=..%2F..%2Ftmp%2Fx");
+    assert_eq!(status, "HTTP/1.1 400 Bad Request");
+    assert_eq!(body["error"], "Invalid database name: \"../../tmp/x\"");
+
+    let (status, _) = get(&server, "evil.example.com", "/databases");
+    assert_eq!(status, "HTTP/1.1 403 Forbidden");
+
+    let (status, body) = get(&server, &host, "/search?db=test&limit=1");
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert_eq!(body.as_array().unwrap().len(), 2);
+    assert_eq!(body[1]["matches"], 1);
+
+    // Invalid configuration files are errors of the server
+    let broken_dir = dir.path().join("config").join("broken");
+    fs::create_dir_all(&broken_dir).unwrap();
+    fs::write(broken_dir.join("config.toml"), "dirs = [").unwrap();
+    let (status, _) = get(&server, &host, "/search?db=broken");
+    assert_eq!(status, "HTTP/1.1 500 Internal Server Error");
+    let (status, _) = get(&server, &host, "/databases");
+    assert_eq!(status, "HTTP/1.1 500 Internal Server Error");
+}